@@ -10,6 +10,10 @@ fn default_port() -> u16 {
     50051
 }
 
+fn default_metrics_port() -> u16 {
+    9090
+}
+
 fn default_flightmngr_url() -> String {
     String::from("grpc://flightmngr:50051")
 }
@@ -22,13 +26,96 @@ fn default_rabbitmq_port() -> u16 {
     5672
 }
 
+fn default_rabbitmq_confirm_timeout_ms() -> u64 {
+    5000
+}
+
+fn default_rabbitmq_buffer_timeout_ms() -> u64 {
+    30000
+}
+
+fn default_outbox_poll_interval_ms() -> u64 {
+    1000
+}
+
+fn default_health_check_interval_ms() -> u64 {
+    5000
+}
+
+fn default_shutdown_drain_ms() -> u64 {
+    5000
+}
+
+fn default_overbooking_factor() -> f64 {
+    1.0
+}
+
+fn default_base_fare_cents() -> i64 {
+    10000
+}
+
+fn default_fare_currency() -> String {
+    String::from("EUR")
+}
+
+fn default_refund_rules() -> String {
+    String::from("168:100,24:50")
+}
+
+fn default_min_connection_time_secs() -> u64 {
+    45 * 60
+}
+
+fn default_checkin_window_secs() -> u64 {
+    24 * 60 * 60
+}
+
+fn default_idempotency_key_ttl_secs() -> u64 {
+    24 * 60 * 60
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum DatabaseBackend {
+    #[default]
+    Mongo,
+    Memory,
+}
+
+/// How ticket fares are computed.
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum PricingStrategyKind {
+    /// `BASE_FARE_CENTS` on every flight.
+    #[default]
+    Fixed,
+    /// Base fare raised by load factor tiers.
+    LoadFactor,
+    /// Base fare raised by tiers of time left before departure.
+    TimeToDeparture,
+}
+
+/// Behaviour of publishes while the broker connection is being re-established.
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum RabbitReconnectPolicy {
+    #[default]
+    Buffer,
+    FailFast,
+}
+
 #[derive(Deserialize, Debug)]
 pub struct Options {
-    pub database_url: String,
+    #[serde(default)]
+    pub database_backend: DatabaseBackend,
+    pub database_url: Option<String>,
     #[serde(default = "default_ip")]
     pub ip: IpAddr,
     #[serde(default = "default_port")]
     pub port: u16,
+    /// Port of the HTTP endpoint serving Prometheus metrics.
+    #[serde(default = "default_metrics_port")]
+    pub metrics_port: u16,
     #[serde(default = "default_flightmngr_url")]
     pub flightmngr_url: String,
     #[serde(default = "default_validationsvc_url")]
@@ -38,4 +125,45 @@ pub struct Options {
     pub rabbitmq_port: u16,
     pub rabbitmq_username: String,
     pub rabbitmq_password: String,
+    #[serde(default = "default_rabbitmq_confirm_timeout_ms")]
+    pub rabbitmq_confirm_timeout_ms: u64,
+    #[serde(default)]
+    pub rabbitmq_reconnect_policy: RabbitReconnectPolicy,
+    #[serde(default = "default_rabbitmq_buffer_timeout_ms")]
+    pub rabbitmq_buffer_timeout_ms: u64,
+    #[serde(default = "default_outbox_poll_interval_ms")]
+    pub outbox_poll_interval_ms: u64,
+    #[serde(default = "default_health_check_interval_ms")]
+    pub health_check_interval_ms: u64,
+    /// Time between reporting NOT_SERVING and stopping the server on shutdown.
+    #[serde(default = "default_shutdown_drain_ms")]
+    pub shutdown_drain_ms: u64,
+    /// Tickets sold per physical seat, unless overridden for a flight.
+    #[serde(default = "default_overbooking_factor")]
+    pub overbooking_factor: f64,
+    #[serde(default)]
+    pub pricing_strategy: PricingStrategyKind,
+    #[serde(default = "default_base_fare_cents")]
+    pub base_fare_cents: i64,
+    /// ISO 4217 code of the currency of the fares.
+    #[serde(default = "default_fare_currency")]
+    pub fare_currency: String,
+    /// Comma separated `<threshold>:<percent of the base fare>` tiers of the pricing strategy,
+    /// thresholds being load factor percentages or hours before departure.
+    pub pricing_tiers: Option<String>,
+    /// Share of the fare refunded on cancellation, as comma separated `<hours>:<percent>`
+    /// tiers of notice before departure.
+    #[serde(default = "default_refund_rules")]
+    pub refund_rules: String,
+    /// Shortest time allowed between two flights of an itinerary.
+    #[serde(default = "default_min_connection_time_secs")]
+    pub min_connection_time_secs: u64,
+    /// How long before departure check-in opens.
+    #[serde(default = "default_checkin_window_secs")]
+    pub checkin_window_secs: u64,
+    /// How long an idempotency key is remembered after the ticket it created.
+    #[serde(default = "default_idempotency_key_ttl_secs")]
+    pub idempotency_key_ttl_secs: u64,
+    /// OTLP gRPC endpoint receiving the trace spans, export is disabled when unset.
+    pub otel_exporter_otlp_endpoint: Option<String>,
 }
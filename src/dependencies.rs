@@ -1,44 +1,63 @@
-use tonic::{transport::Channel, Status};
+use std::time::Instant;
+
+use tonic::{codegen::InterceptedService, transport::Channel, Status};
+
+use crate::metrics::record_upstream_call;
+use crate::telemetry::TraceContextInterceptor;
 
 use crate::proto::{
     flightmngr::{
-        flights_client::FlightsClient, planes_client::PlanesClient, GetFlightRequest,
+        flights_client::FlightsClient, planes_client::PlanesClient, Flight, GetFlightRequest,
         GetPlaneRequest, Plane,
     },
     ticketsrvc::Ticket,
     validationsvc::{validation_client::ValidationClient, SignTicketRequest, SignTicketResponse},
 };
 
+/// Channel to an upstream service, forwarding the trace context of the caller.
+type TracedChannel = InterceptedService<Channel, TraceContextInterceptor>;
+
 #[derive(Debug, Clone)]
 pub struct FlightManager {
-    planes_client: PlanesClient<Channel>,
-    flights_client: FlightsClient<Channel>,
+    planes_client: PlanesClient<TracedChannel>,
+    flights_client: FlightsClient<TracedChannel>,
 }
 
 impl FlightManager {
     pub fn new(channel: Channel) -> Self {
         Self {
-            planes_client: PlanesClient::new(channel.clone()),
-            flights_client: FlightsClient::new(channel),
+            planes_client: PlanesClient::with_interceptor(channel.clone(), TraceContextInterceptor),
+            flights_client: FlightsClient::with_interceptor(channel, TraceContextInterceptor),
         }
     }
 
-    pub async fn get_plane_details(&self, flight_id: String) -> Result<Plane, Status> {
+    pub async fn get_flight(&self, flight_id: String) -> Result<Flight, Status> {
+        let start = Instant::now();
         let flight = self
             .flights_client
             .clone()
             .get_flight(GetFlightRequest { id: flight_id })
-            .await?
-            .into_inner();
+            .await;
+        record_upstream_call("flightmngr", "GetFlight", start, &flight);
+
+        Ok(flight?.into_inner())
+    }
 
+    pub async fn get_plane_details(&self, flight_id: String) -> Result<Plane, Status> {
+        let flight = self.get_flight(flight_id).await?;
+
+        self.get_plane(flight.plane_id).await
+    }
+
+    pub async fn get_plane(&self, plane_id: String) -> Result<Plane, Status> {
+        let start = Instant::now();
         let airplane = self
             .planes_client
             .clone()
-            .get_plane(GetPlaneRequest {
-                id: flight.plane_id,
-            })
-            .await?
-            .into_inner();
+            .get_plane(GetPlaneRequest { id: plane_id })
+            .await;
+        record_upstream_call("flightmngr", "GetPlane", start, &airplane);
+        let airplane = airplane?.into_inner();
 
         Ok(airplane)
     }
@@ -46,25 +65,27 @@ impl FlightManager {
 
 #[derive(Debug, Clone)]
 pub struct ValidationService {
-    pub validation_client: ValidationClient<Channel>,
+    pub validation_client: ValidationClient<TracedChannel>,
 }
 
 impl ValidationService {
     pub fn new(channel: Channel) -> Self {
         Self {
-            validation_client: ValidationClient::new(channel),
+            validation_client: ValidationClient::with_interceptor(channel, TraceContextInterceptor),
         }
     }
 
     pub async fn make_qr_code(&self, ticket: Ticket) -> Result<Vec<u8>, Status> {
-        let SignTicketResponse { qr } = self
+        let start = Instant::now();
+        let response = self
             .validation_client
             .clone()
             .sign_ticket(SignTicketRequest {
                 ticket: Some(ticket.clone()),
             })
-            .await?
-            .into_inner();
+            .await;
+        record_upstream_call("validationsvc", "SignTicket", start, &response);
+        let SignTicketResponse { qr } = response?.into_inner();
 
         Ok(qr)
     }
@@ -14,6 +14,42 @@ pub enum ApplicationError {
 
     #[error("invalid update path: {path}")]
     InvalidUpdatePath { path: String },
+
+    #[error("invalid page token: {details}")]
+    InvalidPageToken { details: &'static str },
+
+    #[error("publish not confirmed: {reason}")]
+    PublishNotConfirmed { reason: String },
+
+    #[error("publish returned as unroutable: {reason}")]
+    PublishReturned { reason: String },
+
+    #[error("rabbitmq broker unavailable")]
+    BrokerUnavailable,
+
+    #[error("idempotency key already in use")]
+    IdempotencyKeyInUse,
+
+    #[error("version conflict: expected {expected}")]
+    VersionConflict { expected: i64 },
+
+    #[error("ticket written by a concurrent request")]
+    ConcurrentWrite,
+
+    #[error("transaction aborted by a write conflict")]
+    WriteConflict,
+
+    #[error("seat already taken")]
+    SeatTaken,
+
+    #[error("no seat available")]
+    NoSeatAvailable,
+
+    #[error("booking reference already in use")]
+    BookingReferenceTaken,
+
+    #[error("booking already cancelled")]
+    BookingCancelled,
 }
 
 impl From<ApplicationError> for tonic::Status {
@@ -21,6 +57,30 @@ impl From<ApplicationError> for tonic::Status {
         match error {
             ApplicationError::NotFound { details } => tonic::Status::not_found(details),
             ApplicationError::InvalidUpdatePath { path } => tonic::Status::invalid_argument(path),
+            ApplicationError::InvalidPageToken { details } => {
+                tonic::Status::invalid_argument(details)
+            }
+            ApplicationError::PublishNotConfirmed { .. }
+            | ApplicationError::PublishReturned { .. }
+            | ApplicationError::BrokerUnavailable => {
+                tracing::error!(%error, "publish failed");
+                tonic::Status::unavailable("event could not be published")
+            }
+            ApplicationError::VersionConflict { .. }
+            | ApplicationError::ConcurrentWrite
+            | ApplicationError::WriteConflict => {
+                tonic::Status::aborted("ticket was modified concurrently, reload it and retry")
+            }
+            ApplicationError::SeatTaken => tonic::Status::already_exists("seat already taken"),
+            ApplicationError::NoSeatAvailable => {
+                tonic::Status::failed_precondition("no seat available")
+            }
+            ApplicationError::BookingCancelled => {
+                tonic::Status::failed_precondition("booking already cancelled")
+            }
+            ApplicationError::IdempotencyKeyInUse => {
+                tonic::Status::aborted("concurrent request with the same idempotency key")
+            }
             _ => {
                 tracing::error!(%error, "internal error");
                 let mut s = tonic::Status::internal("internal error");
@@ -39,4 +99,20 @@ impl ApplicationError {
     pub fn invalid_update_path(path: String) -> Self {
         ApplicationError::InvalidUpdatePath { path }
     }
+
+    pub fn invalid_page_token(details: &'static str) -> Self {
+        ApplicationError::InvalidPageToken { details }
+    }
+
+    pub fn version_conflict(expected: i64) -> Self {
+        ApplicationError::VersionConflict { expected }
+    }
+
+    pub fn publish_not_confirmed(reason: String) -> Self {
+        ApplicationError::PublishNotConfirmed { reason }
+    }
+
+    pub fn publish_returned(reason: String) -> Self {
+        ApplicationError::PublishReturned { reason }
+    }
 }
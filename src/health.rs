@@ -0,0 +1,118 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use tonic::server::NamedService;
+use tonic::transport::Channel;
+use tonic::Code;
+use tonic_health::pb::{health_client::HealthClient, HealthCheckRequest};
+use tonic_health::server::HealthReporter;
+use tonic_health::ServingStatus;
+
+use crate::proto::ticketsrvc::tickets_server::TicketsServer;
+use crate::rabbitmq::Rabbit;
+use crate::tickets::{TicketDatabase, TicketsApp};
+
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Periodically checks the dependencies of the service and reports the result through the
+/// `grpc.health.v1.Health` service.
+///
+/// Every dependency is reported under its own name, while the overall status (the empty
+/// service name and `ticketsrvc.Tickets`) is serving only when all of them are healthy.
+pub struct HealthChecker {
+    reporter: HealthReporter,
+    db: Arc<dyn TicketDatabase>,
+    rabbitmq: Rabbit,
+    flightmngr: Channel,
+    validationsvc: Channel,
+    interval: Duration,
+}
+
+impl HealthChecker {
+    pub fn new(
+        reporter: HealthReporter,
+        db: Arc<dyn TicketDatabase>,
+        rabbitmq: Rabbit,
+        flightmngr: Channel,
+        validationsvc: Channel,
+        interval: Duration,
+    ) -> Self {
+        Self {
+            reporter,
+            db,
+            rabbitmq,
+            flightmngr,
+            validationsvc,
+            interval,
+        }
+    }
+
+    pub async fn run(mut self) {
+        loop {
+            let checks = [
+                ("mongodb", self.check_db().await),
+                ("rabbitmq", self.rabbitmq.is_connected()),
+                ("flightmngr", check_upstream(self.flightmngr.clone()).await),
+                (
+                    "validationsvc",
+                    check_upstream(self.validationsvc.clone()).await,
+                ),
+            ];
+
+            for (name, healthy) in checks {
+                if !healthy {
+                    tracing::warn!(dependency = name, "health check failed");
+                }
+                self.reporter
+                    .set_service_status(name, serving_status(healthy))
+                    .await;
+            }
+
+            let healthy = checks.iter().all(|(_, healthy)| *healthy);
+            set_status(&mut self.reporter, serving_status(healthy)).await;
+
+            tokio::time::sleep(self.interval).await;
+        }
+    }
+
+    async fn check_db(&self) -> bool {
+        match self.db.ping().await {
+            Ok(()) => true,
+            Err(error) => {
+                tracing::warn!(%error, "database ping failed");
+                false
+            }
+        }
+    }
+}
+
+/// Set the overall status of the service.
+pub async fn set_status(reporter: &mut HealthReporter, status: ServingStatus) {
+    reporter.set_service_status("", status).await;
+    reporter
+        .set_service_status(<TicketsServer<TicketsApp> as NamedService>::NAME, status)
+        .await;
+}
+
+fn serving_status(healthy: bool) -> ServingStatus {
+    if healthy {
+        ServingStatus::Serving
+    } else {
+        ServingStatus::NotServing
+    }
+}
+
+/// An upstream is reachable if it answers a health check, whatever the answer: services that
+/// do not implement the health service still respond with `UNIMPLEMENTED`.
+async fn check_upstream(channel: Channel) -> bool {
+    let request = HealthCheckRequest {
+        service: String::new(),
+    };
+    let check = HealthClient::new(channel).check(request);
+
+    match tokio::time::timeout(UPSTREAM_TIMEOUT, check).await {
+        Ok(Ok(_)) => true,
+        Ok(Err(status)) => !matches!(status.code(), Code::Unavailable | Code::Unknown),
+        Err(_) => false,
+    }
+}
@@ -0,0 +1,34 @@
+use prost::Message;
+use sha2::{Digest, Sha256};
+use tonic::metadata::MetadataMap;
+
+use crate::proto::ticketsrvc::Ticket;
+
+/// Metadata key clients may use instead of the request field to supply an idempotency key.
+const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+
+/// Idempotency key of a request, taken from the request field or else from the metadata.
+pub fn idempotency_key(metadata: &MetadataMap, field: String) -> Option<String> {
+    if !field.is_empty() {
+        return Some(field);
+    }
+
+    metadata
+        .get(IDEMPOTENCY_KEY_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .filter(|k| !k.is_empty())
+        .map(String::from)
+}
+
+/// Digest of the ticket a client asked to create, to detect a key reused for another request.
+pub fn fingerprint(ticket: &Option<Ticket>) -> String {
+    let encoded = ticket
+        .as_ref()
+        .map(Message::encode_to_vec)
+        .unwrap_or_default();
+
+    Sha256::digest(encoded)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
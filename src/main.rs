@@ -1,64 +1,147 @@
+use metrics_exporter_prometheus::PrometheusBuilder;
 use mongodb::{
     bson::doc,
     options::{ClientOptions, ServerApi, ServerApiVersion},
     Client,
 };
 use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::net::TcpListener;
 use tokio::signal::unix::{signal, SignalKind};
 use tokio_stream::wrappers::TcpListenerStream;
 use tonic::transport::{Channel, Server};
+use tonic_health::ServingStatus;
 use tower_http::trace;
 use tracing::Level;
 
-use crate::tickets::TicketsApp;
-use crate::{dependencies::FlightManager, rabbitmq::Rabbit};
+use crate::config::DatabaseBackend;
+use crate::dependencies::FlightManager;
+use crate::health::HealthChecker;
+use crate::metrics::{GrpcMetricsLayer, MongoCommandMetrics};
+use crate::rabbitmq::{Consumer, Rabbit, RabbitSettings};
+use crate::tickets::{
+    FlightEventHandler, MemoryDatabase, MongoDatabase, OutboxRelay, TicketDatabase, TicketsApp,
+    TicketsSettings,
+};
 use crate::{dependencies::ValidationService, proto::ticketsrvc::tickets_server::TicketsServer};
 
 mod config;
 mod datautils;
 mod dependencies;
 mod errors;
+mod health;
+mod idempotency;
+mod metrics;
+mod pagination;
 mod parse;
 mod proto;
 mod rabbitmq;
+mod telemetry;
 mod tickets;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    tracing_subscriber::fmt().init();
-
     let opt = envy::from_env::<config::Options>()?;
+    let tickets_settings = TicketsSettings::try_from(&opt)?;
+
+    telemetry::init(opt.otel_exporter_otlp_endpoint.as_deref())?;
+
+    // serve prometheus metrics
+    PrometheusBuilder::new()
+        .with_http_listener(SocketAddr::new(opt.ip, opt.metrics_port))
+        .install()?;
 
     // define db
-    tracing::info!("connecting to mongodb...");
-    let mut client_options = ClientOptions::parse(&opt.database_url).await?;
-    // Set the server_api field of the client_options object to Stable API version 1
-    let server_api = ServerApi::builder().version(ServerApiVersion::V1).build();
-    client_options.server_api = Some(server_api);
-    let client = Client::with_options(client_options)?.database("ticket-svc");
-    client.run_command(doc! { "ping": 1 }, None).await?;
-    tracing::info!("succcessfully connected and pinged mongodb");
+    let db: Arc<dyn TicketDatabase> = match opt.database_backend {
+        DatabaseBackend::Mongo => {
+            let database_url = opt
+                .database_url
+                .as_deref()
+                .ok_or("DATABASE_URL is required by the mongo backend")?;
+
+            tracing::info!("connecting to mongodb...");
+            let mut client_options = ClientOptions::parse(&database_url).await?;
+            // Set the server_api field of the client_options object to Stable API version 1
+            let server_api = ServerApi::builder().version(ServerApiVersion::V1).build();
+            client_options.server_api = Some(server_api);
+            client_options.command_event_handler = Some(Arc::new(MongoCommandMetrics));
+            let client = Client::with_options(client_options)?;
+            client
+                .database("ticket-svc")
+                .run_command(doc! { "ping": 1 }, None)
+                .await?;
+            tracing::info!("succcessfully connected and pinged mongodb");
+
+            let db = MongoDatabase::new(client, "ticket-svc");
+            db.create_indexes().await?;
+            db.migrate_deleted_tickets().await?;
+            Arc::new(db)
+        }
+        DatabaseBackend::Memory => {
+            tracing::warn!("using the in-memory database, tickets are lost on shutdown");
+            Arc::new(MemoryDatabase::default())
+        }
+    };
 
     // Create the rabbitmq channel
     tracing::info!("connecting to rabbitmq broker...");
+    let rabbitmq_settings = RabbitSettings::from(&opt);
     let rabbitmq = Rabbit::new(
-        &opt.rabbitmq_host,
-        opt.rabbitmq_port,
-        &opt.rabbitmq_username,
-        &opt.rabbitmq_password,
+        &rabbitmq_settings,
         String::from("ticket-update"),
         String::from("fanout"),
     )
     .await?;
     tracing::info!("successfully connected to rabbitmq broker and channel created...");
 
+    // publish the ticket updates recorded in the outbox
+    tokio::spawn(
+        OutboxRelay::new(
+            db.clone(),
+            rabbitmq.clone(),
+            Duration::from_millis(opt.outbox_poll_interval_ms),
+        )
+        .run(),
+    );
+
     // define flightmngr grpc client
     let flightmngr_channel = Channel::from_shared(opt.flightmngr_url)?.connect_lazy();
 
+    // apply the flight updates published by flightmngr to the tickets
+    tokio::spawn(
+        Consumer::new(
+            &rabbitmq_settings,
+            String::from("flight-update"),
+            String::from("ticketsvc.flight-update"),
+            FlightEventHandler::new(
+                db.clone(),
+                FlightManager::new(flightmngr_channel.clone()),
+                tickets_settings.overbooking_factor,
+            ),
+        )
+        .run(),
+    );
+
     // define validationsvc grpc client
     let validationsvc_channel = Channel::from_shared(opt.validationsvc_url)?.connect_lazy();
 
+    // report the health of the service and its dependencies
+    let (mut health_reporter, health_service) = tonic_health::server::health_reporter();
+    let health_checker = tokio::spawn(
+        HealthChecker::new(
+            health_reporter.clone(),
+            db.clone(),
+            rabbitmq,
+            flightmngr_channel.clone(),
+            validationsvc_channel.clone(),
+            Duration::from_millis(opt.health_check_interval_ms),
+        )
+        .run(),
+    );
+
+    let shutdown_drain = Duration::from_millis(opt.shutdown_drain_ms);
+
     // bind server socket
     let addr = SocketAddr::new(opt.ip, opt.port);
     let listener = TcpListener::bind(addr).await?;
@@ -70,26 +153,35 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     Server::builder()
         // configure the server
-        .timeout(std::time::Duration::from_secs(10))
+        .timeout(Duration::from_secs(10))
+        .layer(GrpcMetricsLayer)
         .layer(
             trace::TraceLayer::new_for_grpc()
-                .make_span_with(trace::DefaultMakeSpan::new().level(Level::INFO))
+                .make_span_with(telemetry::make_request_span)
                 .on_response(trace::DefaultOnResponse::new().level(Level::INFO)),
         )
         // enable grpc reflection
         .add_service(reflection)
+        .add_service(health_service)
         .add_service(TicketsServer::new(TicketsApp::new(
-            client,
+            db,
             FlightManager::new(flightmngr_channel),
             ValidationService::new(validationsvc_channel),
-            rabbitmq,
+            tickets_settings,
         )))
         // serve
-        .serve_with_incoming_shutdown(TcpListenerStream::new(listener), async {
+        .serve_with_incoming_shutdown(TcpListenerStream::new(listener), async move {
             let _ = signal(SignalKind::terminate()).unwrap().recv().await;
             tracing::info!("shutting down");
+
+            // let load balancers drain traffic before the server stops
+            health_checker.abort();
+            health::set_status(&mut health_reporter, ServingStatus::NotServing).await;
+            tokio::time::sleep(shutdown_drain).await;
         })
         .await?;
 
+    telemetry::shutdown();
+
     Ok(())
 }
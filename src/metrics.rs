@@ -0,0 +1,219 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, Instant};
+
+use mongodb::event::command::{CommandEventHandler, CommandFailedEvent, CommandSucceededEvent};
+use tonic::codegen::http::{self, HeaderMap};
+use tonic::codegen::Body;
+use tonic::Code;
+use tower::{Layer, Service};
+
+/// Layer recording count, latency and status code of every gRPC request served, once its
+/// response is complete.
+#[derive(Debug, Clone, Default)]
+pub struct GrpcMetricsLayer;
+
+impl<S> Layer<S> for GrpcMetricsLayer {
+    type Service = GrpcMetrics<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        GrpcMetrics { inner }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct GrpcMetrics<S> {
+    inner: S,
+}
+
+impl<S, ReqBody, ResBody> Service<http::Request<ReqBody>> for GrpcMetrics<S>
+where
+    S: Service<http::Request<ReqBody>, Response = http::Response<ResBody>>,
+    S::Future: Send + 'static,
+{
+    type Response = http::Response<MetricsBody<ResBody>>;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: http::Request<ReqBody>) -> Self::Future {
+        let call = CallMetrics {
+            method: request.uri().path().to_string(),
+            start: Instant::now(),
+        };
+        let response = self.inner.call(request);
+
+        Box::pin(async move {
+            let response = match response.await {
+                Ok(response) => response,
+                Err(e) => {
+                    call.record(Code::Internal);
+                    return Err(e);
+                }
+            };
+
+            // errors are usually sent as trailers-only responses, with the status in the
+            // headers, otherwise the status comes in the trailers once the body is sent
+            let call = match grpc_status(response.headers()) {
+                Some(code) => {
+                    call.record(code);
+                    None
+                }
+                None => Some(call),
+            };
+
+            Ok(response.map(|body| MetricsBody {
+                inner: Box::pin(body),
+                call,
+            }))
+        })
+    }
+}
+
+/// Request being served, recorded once its status is known.
+struct CallMetrics {
+    method: String,
+    start: Instant,
+}
+
+impl CallMetrics {
+    fn record(self, code: Code) {
+        let code = format!("{code:?}");
+
+        metrics::counter!(
+            "grpc_server_requests_total",
+            "method" => self.method.clone(),
+            "code" => code.clone()
+        )
+        .increment(1);
+        metrics::histogram!(
+            "grpc_server_request_duration_seconds",
+            "method" => self.method,
+            "code" => code
+        )
+        .record(self.start.elapsed().as_secs_f64());
+    }
+}
+
+/// Response body recording the request when it ends, so that streaming calls are recorded
+/// with their final status and their whole duration.
+pub struct MetricsBody<B> {
+    inner: Pin<Box<B>>,
+    /// Taken once recorded.
+    call: Option<CallMetrics>,
+}
+
+impl<B> MetricsBody<B> {
+    fn finish(&mut self, code: Code) {
+        if let Some(call) = self.call.take() {
+            call.record(code);
+        }
+    }
+}
+
+impl<B: Body> Body for MetricsBody<B> {
+    type Data = B::Data;
+    type Error = B::Error;
+
+    fn poll_data(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        let data = ready!(self.inner.as_mut().poll_data(cx));
+        if let Some(Err(_)) = data {
+            self.finish(Code::Internal);
+        }
+        Poll::Ready(data)
+    }
+
+    fn poll_trailers(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+        let trailers = ready!(self.inner.as_mut().poll_trailers(cx));
+        let code = match &trailers {
+            Ok(trailers) => trailers
+                .as_ref()
+                .and_then(grpc_status)
+                .unwrap_or(Code::Unknown),
+            Err(_) => Code::Internal,
+        };
+        self.finish(code);
+        Poll::Ready(trailers)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+}
+
+impl<B> Drop for MetricsBody<B> {
+    fn drop(&mut self) {
+        // the client went away before the end of the response
+        self.finish(Code::Cancelled);
+    }
+}
+
+fn grpc_status(headers: &HeaderMap) -> Option<Code> {
+    headers
+        .get("grpc-status")
+        .map(|s| Code::from_bytes(s.as_bytes()))
+}
+
+/// MongoDB command monitor recording the latency of every database operation.
+#[derive(Debug)]
+pub struct MongoCommandMetrics;
+
+impl CommandEventHandler for MongoCommandMetrics {
+    fn handle_command_succeeded_event(&self, event: CommandSucceededEvent) {
+        record_mongo_command(event.command_name, "success", event.duration);
+    }
+
+    fn handle_command_failed_event(&self, event: CommandFailedEvent) {
+        record_mongo_command(event.command_name, "failure", event.duration);
+    }
+}
+
+fn record_mongo_command(command: String, outcome: &'static str, duration: Duration) {
+    metrics::histogram!(
+        "mongodb_command_duration_seconds",
+        "command" => command,
+        "outcome" => outcome
+    )
+    .record(duration.as_secs_f64());
+}
+
+/// Record what became of a published message.
+pub fn record_rabbitmq_publish(outcome: &'static str) {
+    metrics::counter!("rabbitmq_publish_total", "outcome" => outcome).increment(1);
+}
+
+/// Record what became of a message taken from a queue.
+pub fn record_rabbitmq_consume(outcome: &'static str) {
+    metrics::counter!("rabbitmq_consume_total", "outcome" => outcome).increment(1);
+}
+
+/// Record the latency and outcome of a call to another service.
+pub fn record_upstream_call<T>(
+    service: &'static str,
+    method: &'static str,
+    start: Instant,
+    result: &Result<T, tonic::Status>,
+) {
+    let code = match result {
+        Ok(_) => Code::Ok,
+        Err(status) => status.code(),
+    };
+
+    metrics::histogram!(
+        "upstream_request_duration_seconds",
+        "service" => service,
+        "method" => method,
+        "code" => format!("{code:?}")
+    )
+    .record(start.elapsed().as_secs_f64());
+}
@@ -0,0 +1,138 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use prost::Message;
+use sha2::{Digest, Sha256};
+
+use crate::errors::ApplicationError;
+
+const DEFAULT_PAGE_SIZE: u32 = 50;
+const MAX_PAGE_SIZE: u32 = 1000;
+/// Page tokens older than this are rejected, as the underlying data has likely shifted.
+const PAGE_TOKEN_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// Opaque cursor handed to clients to resume a listing where the previous page ended.
+pub struct PageToken {
+    pub offset: u64,
+    issued_at: u64,
+    /// Digest of the filters of the listing, which the token cannot be used for another.
+    query: String,
+}
+
+impl PageToken {
+    pub fn new(offset: u64, query: String) -> Self {
+        Self {
+            offset,
+            issued_at: now_secs(),
+            query,
+        }
+    }
+
+    pub fn encode(&self) -> String {
+        format!("{}:{}:{}", self.offset, self.issued_at, self.query)
+            .bytes()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    pub fn decode(token: &str, query: &str) -> Result<Self, ApplicationError> {
+        let invalid = || ApplicationError::invalid_page_token("malformed page token");
+
+        if token.len() % 2 != 0 {
+            return Err(invalid());
+        }
+        let bytes = (0..token.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(token.get(i..i + 2)?, 16).ok())
+            .collect::<Option<Vec<u8>>>()
+            .ok_or_else(invalid)?;
+        let decoded = String::from_utf8(bytes).map_err(|_| invalid())?;
+
+        let [offset, issued_at, token_query]: [&str; 3] = decoded
+            .splitn(3, ':')
+            .collect::<Vec<_>>()
+            .try_into()
+            .map_err(|_| invalid())?;
+        let token = Self {
+            offset: offset.parse().map_err(|_| invalid())?,
+            issued_at: issued_at.parse().map_err(|_| invalid())?,
+            query: token_query.to_string(),
+        };
+
+        // the database takes signed offsets
+        if token.offset > i64::MAX as u64 {
+            return Err(invalid());
+        }
+        if token.query != query {
+            return Err(ApplicationError::invalid_page_token(
+                "page token issued for another query",
+            ));
+        }
+        if now_secs().saturating_sub(token.issued_at) > PAGE_TOKEN_TTL.as_secs() {
+            return Err(ApplicationError::invalid_page_token("page token expired"));
+        }
+
+        Ok(token)
+    }
+}
+
+/// Digest identifying the filters of a listing request, given without its paging fields.
+pub fn query_digest(filters: &impl Message) -> String {
+    Sha256::digest(filters.encode_to_vec())
+        .iter()
+        .take(8)
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Parse the page size and token of a listing request into an offset and a page size, the
+/// token having to come from the listing identified by `query`.
+pub fn parse_page(
+    page_size: u32,
+    page_token: &str,
+    query: &str,
+) -> Result<(u64, u32), ApplicationError> {
+    let page_size = match page_size {
+        0 => DEFAULT_PAGE_SIZE,
+        n => n.min(MAX_PAGE_SIZE),
+    };
+
+    let offset = match page_token {
+        "" => 0,
+        token => PageToken::decode(token, query)?.offset,
+    };
+
+    Ok((offset, page_size))
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_resumes_the_same_query() {
+        let token = PageToken::new(100, "abc".to_string()).encode();
+
+        assert_eq!(parse_page(10, &token, "abc").unwrap(), (100, 10));
+    }
+
+    #[test]
+    fn token_rejected_for_another_query() {
+        let token = PageToken::new(100, "abc".to_string()).encode();
+
+        assert!(parse_page(10, &token, "def").is_err());
+    }
+
+    #[test]
+    fn offset_beyond_signed_range_rejected() {
+        let token = PageToken::new(i64::MAX as u64 + 1, "abc".to_string()).encode();
+
+        assert!(parse_page(10, &token, "abc").is_err());
+    }
+}
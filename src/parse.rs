@@ -1,5 +1,6 @@
 use prost_types::FieldMask;
 use std::collections::BTreeSet;
+use std::str::FromStr;
 use tonic::Status;
 
 pub fn parse_update_paths(update_mask: Option<FieldMask>) -> Result<BTreeSet<String>, Status> {
@@ -14,3 +15,26 @@ pub fn parse_update_paths(update_mask: Option<FieldMask>) -> Result<BTreeSet<Str
         Ok(update_paths)
     }
 }
+
+/// Parse comma separated `<threshold>:<percent>` tiers, as used in the fare rules.
+pub fn parse_tiers<T: FromStr>(s: &str) -> Result<Vec<(T, u32)>, String> {
+    s.split(',')
+        .map(str::trim)
+        .filter(|t| !t.is_empty())
+        .map(|t| {
+            let (threshold, percent) = t
+                .split_once(':')
+                .ok_or_else(|| format!("tier {t:?} is not <threshold>:<percent>"))?;
+            let threshold = threshold
+                .trim()
+                .parse()
+                .map_err(|_| format!("invalid threshold in tier {t:?}"))?;
+            let percent = percent
+                .trim()
+                .parse()
+                .map_err(|_| format!("invalid percentage in tier {t:?}"))?;
+
+            Ok((threshold, percent))
+        })
+        .collect()
+}
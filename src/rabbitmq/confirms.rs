@@ -0,0 +1,158 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+
+use amqprs::{
+    callbacks::ChannelCallback, channel::Channel, error::Error, Ack, BasicProperties, Cancel,
+    CloseChannel, Nack, Return,
+};
+use tokio::sync::{oneshot, Notify};
+use tonic::async_trait;
+
+/// Outcome of a publish once the broker has taken responsibility for it (or refused it).
+pub enum Confirm {
+    Ack,
+    Nack,
+    Returned(String),
+}
+
+/// Publish waiting for the broker to confirm it.
+struct Waiting {
+    /// Message id of the publish, by which the broker hands it back if it cannot be routed.
+    message_id: String,
+    confirm: oneshot::Sender<Confirm>,
+}
+
+/// Publishes waiting for the broker to confirm them, keyed by delivery tag.
+#[derive(Default)]
+pub struct PendingConfirms {
+    last_tag: u64,
+    waiting: BTreeMap<u64, Waiting>,
+    returned: HashMap<u64, String>,
+}
+
+impl PendingConfirms {
+    /// Register the next publish on the channel, returning its delivery tag and the receiver
+    /// of its confirmation. Tags are assigned by the broker in publish order, starting at 1,
+    /// so the channel cannot be used again if a publish fails to go out.
+    pub fn register(&mut self, message_id: &str) -> (u64, oneshot::Receiver<Confirm>) {
+        self.last_tag += 1;
+        let (tx, rx) = oneshot::channel();
+        self.waiting.insert(
+            self.last_tag,
+            Waiting {
+                message_id: message_id.to_string(),
+                confirm: tx,
+            },
+        );
+        (self.last_tag, rx)
+    }
+
+    pub fn forget(&mut self, delivery_tag: u64) {
+        self.waiting.remove(&delivery_tag);
+        self.returned.remove(&delivery_tag);
+    }
+
+    /// Fail every publish still waiting, when the channel is given up.
+    pub fn abandon(&mut self) {
+        self.waiting.clear();
+        self.returned.clear();
+    }
+
+    /// Remember a publish as returned, to report it once the broker confirms it.
+    fn mark_returned(&mut self, message_id: &str, reason: String) -> bool {
+        let delivery_tag = self
+            .waiting
+            .iter()
+            .rev()
+            .find(|(_, w)| w.message_id == message_id)
+            .map(|(t, _)| *t);
+        match delivery_tag {
+            Some(delivery_tag) => {
+                self.returned.insert(delivery_tag, reason);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn resolve(&mut self, delivery_tag: u64, multiple: bool, acked: bool) {
+        let tags: Vec<u64> = if multiple {
+            self.waiting
+                .range(..=delivery_tag)
+                .map(|(t, _)| *t)
+                .collect()
+        } else {
+            vec![delivery_tag]
+        };
+
+        for tag in tags {
+            let returned = self.returned.remove(&tag);
+            if let Some(waiting) = self.waiting.remove(&tag) {
+                let confirm = match (acked, returned) {
+                    (_, Some(reason)) => Confirm::Returned(reason),
+                    (true, None) => Confirm::Ack,
+                    (false, None) => Confirm::Nack,
+                };
+                let _ = waiting.confirm.send(confirm);
+            }
+        }
+    }
+}
+
+/// Channel callback forwarding publisher confirms and returned messages to the publishers.
+pub struct ConfirmCallback {
+    pub pending: Arc<Mutex<PendingConfirms>>,
+    /// Notified when the broker closes the channel, so that it gets reopened.
+    pub closed: Arc<Notify>,
+}
+
+#[async_trait]
+impl ChannelCallback for ConfirmCallback {
+    async fn close(&mut self, _channel: &Channel, close: CloseChannel) -> Result<(), Error> {
+        tracing::error!(%close, "rabbitmq channel closed by the broker");
+        self.closed.notify_one();
+        Ok(())
+    }
+
+    async fn cancel(&mut self, _channel: &Channel, _cancel: Cancel) -> Result<(), Error> {
+        Ok(())
+    }
+
+    async fn flow(&mut self, _channel: &Channel, active: bool) -> Result<bool, Error> {
+        Ok(active)
+    }
+
+    async fn publish_ack(&mut self, _channel: &Channel, ack: Ack) {
+        self.pending
+            .lock()
+            .unwrap()
+            .resolve(ack.delivery_tag(), ack.mutiple(), true);
+    }
+
+    async fn publish_nack(&mut self, _channel: &Channel, nack: Nack) {
+        self.pending
+            .lock()
+            .unwrap()
+            .resolve(nack.delivery_tag(), nack.multiple(), false);
+    }
+
+    async fn publish_return(
+        &mut self,
+        _channel: &Channel,
+        ret: Return,
+        basic_properties: BasicProperties,
+        _content: Vec<u8>,
+    ) {
+        // the return is always delivered before the ack of the same message, and the message
+        // id tells which publish it was
+        let returned = basic_properties.message_id().is_some_and(|message_id| {
+            self.pending
+                .lock()
+                .unwrap()
+                .mark_returned(message_id, ret.reply_text().to_string())
+        });
+        if !returned {
+            tracing::warn!(%ret, "unroutable message returned by the broker");
+        }
+    }
+}
@@ -0,0 +1,283 @@
+use std::sync::Arc;
+
+use amqprs::{
+    callbacks::{DefaultChannelCallback, DefaultConnectionCallback},
+    channel::{
+        BasicAckArguments, BasicConsumeArguments, BasicNackArguments, BasicQosArguments,
+        BasicRejectArguments, Channel, ExchangeDeclareArguments, QueueBindArguments,
+        QueueDeclareArguments,
+    },
+    connection::{Connection, OpenConnectionArguments},
+    consumer::AsyncConsumer,
+    BasicProperties, Deliver, FieldTable, FieldValue,
+};
+use backon::{ExponentialBuilder, Retryable};
+use thiserror::Error;
+use tonic::async_trait;
+use tracing::Instrument;
+
+use crate::errors::ApplicationError;
+use crate::metrics::record_rabbitmq_consume;
+
+use super::{RabbitSettings, HEALTH_CHECK_INTERVAL};
+
+/// Messages delivered to the consumer at once, before any is acknowledged.
+const PREFETCH_COUNT: u16 = 16;
+
+/// Why a message could not be handled.
+#[derive(Error, Debug)]
+pub enum HandleError {
+    /// The message can never be handled, e.g. it cannot be decoded.
+    #[error("malformed message: {0}")]
+    Malformed(String),
+    /// Handling the message may succeed later.
+    #[error("{0}")]
+    Transient(String),
+}
+
+impl From<ApplicationError> for HandleError {
+    fn from(error: ApplicationError) -> Self {
+        HandleError::Transient(error.to_string())
+    }
+}
+
+impl From<tonic::Status> for HandleError {
+    fn from(status: tonic::Status) -> Self {
+        HandleError::Transient(status.to_string())
+    }
+}
+
+/// Processing applied to each message taken from the queue.
+///
+/// A message may be delivered more than once, handlers use its id to process it only once.
+#[async_trait]
+pub trait MessageHandler: Send + Sync + 'static {
+    async fn handle(&self, message_id: &str, content: &[u8]) -> Result<(), HandleError>;
+}
+
+/// Consumer binding a durable queue to an exchange, reconnecting in the background when the
+/// broker connection or channel is lost.
+///
+/// Messages are acknowledged once handled. Those that are malformed, or still fail when
+/// redelivered, are dead-lettered to the `<queue>.dead-letter` queue for inspection.
+pub struct Consumer<H> {
+    connection_arguments: OpenConnectionArguments,
+    exchange_name: String,
+    queue_name: String,
+    handler: Arc<H>,
+}
+
+struct ConsumerLink {
+    connection: Connection,
+    channel: Channel,
+}
+
+impl ConsumerLink {
+    fn is_open(&self) -> bool {
+        self.connection.is_open() && self.channel.is_open()
+    }
+}
+
+impl<H: MessageHandler> Consumer<H> {
+    pub fn new(
+        settings: &RabbitSettings,
+        exchange_name: String,
+        queue_name: String,
+        handler: H,
+    ) -> Self {
+        Self {
+            connection_arguments: settings.connection_arguments(),
+            exchange_name,
+            queue_name,
+            handler: Arc::new(handler),
+        }
+    }
+
+    pub async fn run(self) {
+        loop {
+            let link = (|| async { self.open_link().await })
+                .retry(&ExponentialBuilder::default().with_max_times(usize::MAX))
+                .notify(|error, after| {
+                    tracing::warn!(%error, ?after, queue = %self.queue_name, "failed to start consuming")
+                })
+                .await;
+            let link = match link {
+                Ok(link) => link,
+                Err(error) => {
+                    tracing::error!(%error, queue = %self.queue_name, "giving up consuming");
+                    return;
+                }
+            };
+            tracing::info!(queue = %self.queue_name, "consuming from rabbitmq");
+
+            while link.is_open() {
+                tokio::time::sleep(HEALTH_CHECK_INTERVAL).await;
+            }
+            tracing::warn!(queue = %self.queue_name, "rabbitmq consumer connection lost, reconnecting...");
+        }
+    }
+
+    /// Open a connection and a channel, declare the queues and start consuming on it.
+    async fn open_link(&self) -> Result<ConsumerLink, amqprs::error::Error> {
+        let connection = Connection::open(&self.connection_arguments).await?;
+        connection
+            .register_callback(DefaultConnectionCallback)
+            .await?;
+
+        let channel = connection.open_channel(None).await?;
+        channel.register_callback(DefaultChannelCallback).await?;
+
+        // the exchange belongs to the publisher, wait until it has declared it
+        channel
+            .exchange_declare(ExchangeDeclareArguments {
+                exchange: self.exchange_name.clone(),
+                exchange_type: String::from("fanout"),
+                passive: true,
+                durable: true,
+                auto_delete: false,
+                internal: false,
+                no_wait: false,
+                arguments: FieldTable::default(),
+            })
+            .await?;
+
+        // messages rejected by the handler end up in the dead letter queue
+        let dead_letter = format!("{}.dead-letter", self.queue_name);
+        channel
+            .exchange_declare(ExchangeDeclareArguments {
+                exchange: dead_letter.clone(),
+                exchange_type: String::from("fanout"),
+                passive: false,
+                durable: true,
+                auto_delete: false,
+                internal: false,
+                no_wait: false,
+                arguments: FieldTable::default(),
+            })
+            .await?;
+        channel
+            .queue_declare(QueueDeclareArguments {
+                queue: dead_letter.clone(),
+                passive: false,
+                durable: true,
+                exclusive: false,
+                auto_delete: false,
+                no_wait: false,
+                arguments: FieldTable::default(),
+            })
+            .await?;
+        channel
+            .queue_bind(QueueBindArguments::new(&dead_letter, &dead_letter, ""))
+            .await?;
+
+        let mut arguments = FieldTable::new();
+        arguments.insert(
+            "x-dead-letter-exchange".try_into().unwrap(),
+            FieldValue::S(dead_letter.try_into().unwrap()),
+        );
+        channel
+            .queue_declare(QueueDeclareArguments {
+                queue: self.queue_name.clone(),
+                passive: false,
+                durable: true, // survive broker restart
+                exclusive: false,
+                auto_delete: false,
+                no_wait: false,
+                arguments,
+            })
+            .await?;
+        channel
+            .queue_bind(QueueBindArguments::new(
+                &self.queue_name,
+                &self.exchange_name,
+                "",
+            ))
+            .await?;
+
+        channel
+            .basic_qos(BasicQosArguments::new(0, PREFETCH_COUNT, false))
+            .await?;
+        let mut consume_arguments = BasicConsumeArguments::new(&self.queue_name, "");
+        // acknowledge each message once handled
+        consume_arguments.no_ack = false;
+        channel
+            .basic_consume(
+                Dispatcher {
+                    handler: self.handler.clone(),
+                },
+                consume_arguments,
+            )
+            .await?;
+
+        Ok(ConsumerLink {
+            connection,
+            channel,
+        })
+    }
+}
+
+/// Hands the delivered messages to the handler and settles them with the broker.
+struct Dispatcher<H> {
+    handler: Arc<H>,
+}
+
+#[async_trait]
+impl<H: MessageHandler> AsyncConsumer for Dispatcher<H> {
+    async fn consume(
+        &mut self,
+        channel: &Channel,
+        deliver: Deliver,
+        basic_properties: BasicProperties,
+        content: Vec<u8>,
+    ) {
+        let delivery_tag = deliver.delivery_tag();
+
+        // without an id a redelivery cannot be told from a new message with the same content
+        let Some(message_id) = basic_properties.message_id().filter(|id| !id.is_empty()) else {
+            tracing::error!(delivery_tag, "message without id, dead-lettering");
+            record_rabbitmq_consume("dead_lettered");
+            if let Err(error) = channel
+                .basic_reject(BasicRejectArguments::new(delivery_tag, false))
+                .await
+            {
+                tracing::error!(%error, delivery_tag, "failed to settle message");
+            }
+            return;
+        };
+        let span = tracing::info_span!("consume message", %message_id);
+
+        let handler = &self.handler;
+        let result = (|| async { handler.handle(message_id, &content).await })
+            .retry(&ExponentialBuilder::default().with_max_times(5))
+            .when(|e| matches!(e, HandleError::Transient(_)))
+            .instrument(span)
+            .await;
+
+        let settled = match result {
+            Ok(()) => {
+                record_rabbitmq_consume("handled");
+                channel
+                    .basic_ack(BasicAckArguments::new(delivery_tag, false))
+                    .await
+            }
+            // give the message a second chance before dead-lettering it
+            Err(error @ HandleError::Transient(_)) if !deliver.redelivered() => {
+                tracing::warn!(%error, %message_id, "failed to handle message, requeuing");
+                record_rabbitmq_consume("requeued");
+                channel
+                    .basic_nack(BasicNackArguments::new(delivery_tag, false, true))
+                    .await
+            }
+            Err(error) => {
+                tracing::error!(%error, %message_id, "failed to handle message, dead-lettering");
+                record_rabbitmq_consume("dead_lettered");
+                channel
+                    .basic_reject(BasicRejectArguments::new(delivery_tag, false))
+                    .await
+            }
+        };
+        if let Err(error) = settled {
+            tracing::error!(%error, %message_id, "failed to settle message");
+        }
+    }
+}
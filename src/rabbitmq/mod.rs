@@ -0,0 +1,357 @@
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use amqprs::{
+    callbacks::DefaultConnectionCallback,
+    channel::{BasicPublishArguments, Channel, ConfirmSelectArguments, ExchangeDeclareArguments},
+    connection::{Connection, OpenConnectionArguments},
+    BasicProperties, FieldTable, FieldValue,
+};
+use backon::{ExponentialBuilder, Retryable};
+use prost::Message;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{watch, Notify};
+
+use crate::config::{Options, RabbitReconnectPolicy};
+use crate::metrics::record_rabbitmq_publish;
+use crate::telemetry;
+use crate::{errors::ApplicationError, proto::ticketsrvc::Ticket};
+
+use self::confirms::{Confirm, ConfirmCallback, PendingConfirms};
+
+pub use self::consumer::{Consumer, HandleError, MessageHandler};
+
+mod confirms;
+mod consumer;
+
+/// How often the connection is checked when no failure was reported.
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// What to do with publishes while the connection to the broker is down.
+#[derive(Debug, Clone, Copy)]
+pub enum ReconnectPolicy {
+    /// Fail immediately.
+    FailFast,
+    /// Wait up to the given duration for the connection to be restored.
+    Buffer(Duration),
+}
+
+/// How to reach the broker, shared by the publisher and the consumers.
+#[derive(Clone)]
+pub struct RabbitSettings {
+    pub host: String,
+    pub port: u16,
+    pub username: String,
+    pub password: String,
+    /// How long a publish waits for the broker to confirm it.
+    pub confirm_timeout: Duration,
+    pub reconnect_policy: ReconnectPolicy,
+}
+
+impl From<&Options> for RabbitSettings {
+    fn from(opt: &Options) -> Self {
+        let reconnect_policy = match opt.rabbitmq_reconnect_policy {
+            RabbitReconnectPolicy::Buffer => {
+                ReconnectPolicy::Buffer(Duration::from_millis(opt.rabbitmq_buffer_timeout_ms))
+            }
+            RabbitReconnectPolicy::FailFast => ReconnectPolicy::FailFast,
+        };
+
+        Self {
+            host: opt.rabbitmq_host.clone(),
+            port: opt.rabbitmq_port,
+            username: opt.rabbitmq_username.clone(),
+            password: opt.rabbitmq_password.clone(),
+            confirm_timeout: Duration::from_millis(opt.rabbitmq_confirm_timeout_ms),
+            reconnect_policy,
+        }
+    }
+}
+
+impl RabbitSettings {
+    fn connection_arguments(&self) -> OpenConnectionArguments {
+        OpenConnectionArguments::new(&self.host, self.port, &self.username, &self.password)
+    }
+}
+
+/// Publisher on the ticket-update exchange, reconnecting in the background when the broker
+/// connection or channel is lost.
+#[derive(Clone)]
+pub struct Rabbit {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    connection_arguments: OpenConnectionArguments,
+    exchange_name: String,
+    exchange_type: String,
+    confirm_timeout: Duration,
+    reconnect_policy: ReconnectPolicy,
+    // publishes must reach the channel in the order their delivery tags were assigned
+    link: tokio::sync::Mutex<Option<Link>>,
+    connected: watch::Sender<bool>,
+    link_failed: Arc<Notify>,
+}
+
+struct Link {
+    connection: Connection,
+    channel: Channel,
+    pending_confirms: Arc<Mutex<PendingConfirms>>,
+}
+
+impl Link {
+    fn is_open(&self) -> bool {
+        self.connection.is_open() && self.channel.is_open()
+    }
+
+    /// Give the link up, failing the publishes still waiting for a confirmation on it.
+    async fn close(self) {
+        self.pending_confirms.lock().unwrap().abandon();
+        if let Err(error) = self.connection.close().await {
+            tracing::debug!(%error, "failed to close the rabbitmq connection");
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub enum UpdateKind {
+    Create = 0,
+    Update = 1,
+    Delete = 2,
+    StatusChange = 3,
+}
+
+impl Rabbit {
+    pub async fn new(
+        settings: &RabbitSettings,
+        exchange_name: String,
+        exchange_type: String,
+    ) -> Result<Self, Box<dyn Error>> {
+        let (connected, _) = watch::channel(false);
+        let inner = Arc::new(Inner {
+            connection_arguments: settings.connection_arguments(),
+            exchange_name,
+            exchange_type,
+            confirm_timeout: settings.confirm_timeout,
+            reconnect_policy: settings.reconnect_policy,
+            link: tokio::sync::Mutex::new(None),
+            connected,
+            link_failed: Arc::new(Notify::new()),
+        });
+
+        let link = (|| async { inner.open_link().await })
+            .retry(&ExponentialBuilder::default().with_max_times(10))
+            .await?;
+        *inner.link.lock().await = Some(link);
+        inner.connected.send_replace(true);
+
+        tokio::spawn(inner.clone().supervise());
+
+        Ok(Rabbit { inner })
+    }
+
+    /// Whether the connection and channel to the broker are currently open.
+    pub fn is_connected(&self) -> bool {
+        *self.inner.connected.borrow()
+    }
+
+    /// Publish a ticket update. The message id lets consumers tell redeliveries of the same
+    /// update apart from new ones.
+    pub async fn notify_ticket_update(
+        &self,
+        message_id: &str,
+        message: Ticket,
+        update_kind: UpdateKind,
+    ) -> Result<(), ApplicationError> {
+        let result = self.publish(message_id, message, update_kind).await;
+        record_rabbitmq_publish(match &result {
+            Ok(()) => "confirmed",
+            Err(ApplicationError::PublishReturned { .. }) => "returned",
+            Err(_) => "failed",
+        });
+        result
+    }
+
+    async fn publish(
+        &self,
+        message_id: &str,
+        message: Ticket,
+        update_kind: UpdateKind,
+    ) -> Result<(), ApplicationError> {
+        self.inner.wait_connected().await?;
+
+        let message = message.encode_to_vec();
+
+        let mut args = BasicPublishArguments::new(&self.inner.exchange_name, "");
+        // get the message back if no queue is bound to the exchange
+        args.mandatory = true;
+
+        let mut ft = FieldTable::new();
+        ft.insert(
+            "x-update-kind".try_into().unwrap(),
+            FieldValue::B(update_kind as u8),
+        );
+        telemetry::inject_amqp_headers(&mut ft);
+
+        let start = Instant::now();
+        // a timed out publish is forgotten by the channel it went out on, even if the link
+        // has been replaced since
+        let (delivery_tag, confirm, pending_confirms) = {
+            let mut guard = self.inner.link.lock().await;
+            let Some(link) = guard.as_ref().filter(|l| l.is_open()) else {
+                self.inner.link_failed.notify_one();
+                return Err(ApplicationError::BrokerUnavailable);
+            };
+            let (delivery_tag, confirm) =
+                link.pending_confirms.lock().unwrap().register(message_id);
+
+            let properties = BasicProperties::default()
+                .with_content_type("application/x-protobuf")
+                .with_message_type("ticketsrvc.Ticket")
+                .with_message_id(message_id)
+                .with_headers(ft)
+                .finish();
+
+            if let Err(e) = link.channel.basic_publish(properties, message, args).await {
+                // the broker may have counted the publish or not, so the delivery tags of the
+                // channel can no longer be told: have the supervisor open another one
+                if let Some(link) = guard.take() {
+                    link.close().await;
+                }
+                self.inner.link_failed.notify_one();
+                return Err(e.into());
+            }
+            (delivery_tag, confirm, link.pending_confirms.clone())
+        };
+
+        let reason = match tokio::time::timeout(self.inner.confirm_timeout, confirm).await {
+            Ok(Ok(Confirm::Ack)) => {
+                tracing::debug!(
+                    delivery_tag,
+                    latency_ms = start.elapsed().as_millis() as u64,
+                    "publish confirmed"
+                );
+                return Ok(());
+            }
+            Ok(Ok(Confirm::Nack)) => String::from("rejected by the broker"),
+            // no queue is bound to the exchange, publishing again would not change that
+            Ok(Ok(Confirm::Returned(reason))) => {
+                return Err(ApplicationError::publish_returned(reason))
+            }
+            Ok(Err(_)) => String::from("channel closed before confirmation"),
+            Err(_) => {
+                pending_confirms.lock().unwrap().forget(delivery_tag);
+                String::from("timed out waiting for confirmation")
+            }
+        };
+
+        tracing::warn!(
+            delivery_tag,
+            latency_ms = start.elapsed().as_millis() as u64,
+            %reason,
+            "publish not confirmed"
+        );
+        Err(ApplicationError::publish_not_confirmed(reason))
+    }
+}
+
+impl Inner {
+    /// Open a connection and a confirm-mode channel, and declare the exchange on it.
+    async fn open_link(&self) -> Result<Link, amqprs::error::Error> {
+        let connection = Connection::open(&self.connection_arguments).await?;
+
+        // Register connection level callbacks.
+        connection
+            .register_callback(DefaultConnectionCallback)
+            .await?;
+
+        // open a channel on the connection
+        let channel = connection.open_channel(None).await?;
+        let pending_confirms = Arc::new(Mutex::new(PendingConfirms::default()));
+        channel
+            .register_callback(ConfirmCallback {
+                pending: pending_confirms.clone(),
+                closed: self.link_failed.clone(),
+            })
+            .await?;
+        // have the broker acknowledge every message it takes responsibility for
+        channel
+            .confirm_select(ConfirmSelectArguments::new(false))
+            .await?;
+
+        // declare the exchange in which to publish new or modified tickets
+        channel
+            .exchange_declare(ExchangeDeclareArguments {
+                exchange: self.exchange_name.clone(),
+                exchange_type: self.exchange_type.clone(),
+                passive: false, // if does not exist, then is created. If set to true, an error is raised if exchange does not exist
+                durable: true,  // survive broker restart
+                auto_delete: false, // survive even if no queue is bound
+                internal: false,
+                no_wait: false,
+                arguments: FieldTable::default(),
+            })
+            .await?;
+
+        Ok(Link {
+            connection,
+            channel,
+            pending_confirms,
+        })
+    }
+
+    /// Watch the link, re-establishing it with backoff whenever it is found closed.
+    async fn supervise(self: Arc<Self>) {
+        loop {
+            tokio::select! {
+                _ = self.link_failed.notified() => {}
+                _ = tokio::time::sleep(HEALTH_CHECK_INTERVAL) => {}
+            }
+
+            {
+                let mut link = self.link.lock().await;
+                if link.as_ref().is_some_and(Link::is_open) {
+                    continue;
+                }
+                *link = None;
+            }
+
+            tracing::warn!("rabbitmq connection lost, reconnecting...");
+            self.connected.send_replace(false);
+
+            let new_link = (|| async { self.open_link().await })
+                .retry(&ExponentialBuilder::default().with_max_times(usize::MAX))
+                .notify(|error, after| {
+                    tracing::warn!(%error, ?after, "failed to reconnect to rabbitmq")
+                })
+                .await;
+            match new_link {
+                Ok(new_link) => {
+                    *self.link.lock().await = Some(new_link);
+                    self.connected.send_replace(true);
+                    tracing::info!("reconnected to rabbitmq broker");
+                }
+                Err(error) => tracing::error!(%error, "giving up reconnecting to rabbitmq"),
+            }
+        }
+    }
+
+    /// Apply the reconnect policy when the link is down.
+    async fn wait_connected(&self) -> Result<(), ApplicationError> {
+        if *self.connected.borrow() {
+            return Ok(());
+        }
+
+        match self.reconnect_policy {
+            ReconnectPolicy::FailFast => Err(ApplicationError::BrokerUnavailable),
+            ReconnectPolicy::Buffer(timeout) => {
+                let mut connected = self.connected.subscribe();
+                match tokio::time::timeout(timeout, connected.wait_for(|c| *c)).await {
+                    Ok(Ok(_)) => Ok(()),
+                    _ => Err(ApplicationError::BrokerUnavailable),
+                }
+            }
+        }
+    }
+}
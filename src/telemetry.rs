@@ -0,0 +1,150 @@
+use std::collections::HashMap;
+
+use amqprs::{FieldTable, FieldValue};
+use opentelemetry::propagation::{Extractor, Injector};
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry::{global, Context, KeyValue};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::{propagation::TraceContextPropagator, runtime, trace, Resource};
+use tonic::codegen::http;
+use tonic::metadata::{MetadataKey, MetadataMap, MetadataValue};
+use tonic::service::Interceptor;
+use tonic::{Request, Status};
+use tracing::Span;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+
+const SERVICE_NAME: &str = "ticketsvc";
+
+/// Install the log subscriber and the W3C trace context propagator.
+///
+/// Spans are exported over OTLP when an endpoint is given; otherwise trace ids are still
+/// generated and propagated, but nothing is exported.
+pub fn init(otlp_endpoint: Option<&str>) -> Result<(), Box<dyn std::error::Error>> {
+    global::set_text_map_propagator(TraceContextPropagator::new());
+
+    let tracer = match otlp_endpoint {
+        Some(endpoint) => otlp_tracer(endpoint)?,
+        None => {
+            let provider = trace::TracerProvider::builder().build();
+            let tracer = provider.tracer(SERVICE_NAME);
+            global::set_tracer_provider(provider);
+            tracer
+        }
+    };
+
+    tracing_subscriber::registry()
+        .with(LevelFilter::INFO)
+        .with(tracing_subscriber::fmt::layer())
+        .with(tracing_opentelemetry::layer().with_tracer(tracer))
+        .try_init()?;
+
+    Ok(())
+}
+
+fn otlp_tracer(endpoint: &str) -> Result<trace::Tracer, opentelemetry::trace::TraceError> {
+    opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(endpoint),
+        )
+        .with_trace_config(
+            trace::config().with_resource(Resource::new(vec![KeyValue::new(
+                "service.name",
+                SERVICE_NAME,
+            )])),
+        )
+        .install_batch(runtime::Tokio)
+}
+
+/// Flush the spans not exported yet.
+pub fn shutdown() {
+    global::shutdown_tracer_provider();
+}
+
+/// Span of an incoming gRPC request, child of the trace context sent by the caller if any.
+pub fn make_request_span<B>(request: &http::Request<B>) -> Span {
+    let span = tracing::info_span!(
+        "request",
+        method = %request.method(),
+        uri = %request.uri(),
+        version = ?request.version(),
+    );
+
+    let parent =
+        global::get_text_map_propagator(|p| p.extract(&HeaderExtractor(request.headers())));
+    span.set_parent(parent);
+    span
+}
+
+/// Trace context of the current span, in a form that can be stored alongside an event.
+pub fn current_context() -> HashMap<String, String> {
+    let mut carrier = HashMap::new();
+    inject(&mut carrier);
+    carrier
+}
+
+/// Trace context previously saved with [`current_context`].
+pub fn extract_context(carrier: &HashMap<String, String>) -> Context {
+    global::get_text_map_propagator(|p| p.extract(carrier))
+}
+
+/// Add the trace context of the current span to the headers of an AMQP message.
+pub fn inject_amqp_headers(headers: &mut FieldTable) {
+    inject(&mut FieldTableInjector(headers));
+}
+
+fn inject(injector: &mut dyn Injector) {
+    let context = Span::current().context();
+    global::get_text_map_propagator(|p| p.inject_context(&context, injector));
+}
+
+/// Client interceptor sending the trace context of the current span to the upstream service.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TraceContextInterceptor;
+
+impl Interceptor for TraceContextInterceptor {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        inject(&mut MetadataInjector(request.metadata_mut()));
+        Ok(request)
+    }
+}
+
+struct HeaderExtractor<'a>(&'a http::HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|v| v.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|k| k.as_str()).collect()
+    }
+}
+
+struct MetadataInjector<'a>(&'a mut MetadataMap);
+
+impl Injector for MetadataInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(key), Ok(value)) = (
+            MetadataKey::from_bytes(key.as_bytes()),
+            MetadataValue::try_from(value),
+        ) {
+            self.0.insert(key, value);
+        }
+    }
+}
+
+struct FieldTableInjector<'a>(&'a mut FieldTable);
+
+impl Injector for FieldTableInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(key), Ok(value)) = (key.try_into(), value.try_into()) {
+            self.0.insert(key, FieldValue::S(value));
+        }
+    }
+}
@@ -1,16 +1,22 @@
-use mongodb::bson::{doc, oid::ObjectId, DateTime};
-use mongodb::{Collection, Database};
+use mongodb::bson::{oid::ObjectId, DateTime};
+use rand::distributions::{Alphanumeric, DistString};
+use rand::Rng;
 use serde::{Deserialize, Serialize};
-use std::collections::BTreeSet;
-use tokio_stream::StreamExt;
+use std::collections::{BTreeSet, HashMap};
+use std::pin::Pin;
+use std::time::{Duration, SystemTime};
+use tokio_stream::Stream;
 use tonic::async_trait;
 
 use crate::errors::ApplicationError;
 use crate::proto::ticketsrvc::TicketStatus;
+use crate::rabbitmq::UpdateKind;
+use crate::telemetry;
 
-type DbResult<T> = std::result::Result<T, ApplicationError>;
+pub type DbResult<T> = std::result::Result<T, ApplicationError>;
+pub type TicketStream = Pin<Box<dyn Stream<Item = DbResult<Ticket>> + Send>>;
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct Ticket {
     pub _id: ObjectId,
     pub url: String,
@@ -19,9 +25,60 @@ pub struct Ticket {
     pub reservation_datetime: DateTime,
     pub estimated_cargo_weight: u32,
     pub ticket_status: String,
+    /// Price paid for the ticket, in cents of `currency`.
+    #[serde(default)]
+    pub fare_cents: i64,
+    /// ISO 4217 code of the currency of the fare.
+    #[serde(default)]
+    pub currency: String,
+    /// Incremented on every change, so that writes can be made conditional on it. Tickets
+    /// stored before versioning was introduced have no version and read as 0.
+    #[serde(default)]
+    pub version: i64,
+    /// Reference shared by the tickets booked together.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub booking_reference: Option<String>,
+    /// Seat assigned to the passenger, unique among the valid tickets of the flight.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seat: Option<String>,
+    /// Set when the passenger checks in.
+    #[serde(default)]
+    pub checked_in_at: Option<DateTime>,
+    /// Set when the ticket is deleted; deleted tickets are kept with a `Deleted` status.
+    #[serde(default)]
+    pub deleted_at: Option<DateTime>,
+    /// Set when the flight no longer has a seat for the ticket, after an aircraft change.
+    #[serde(default)]
+    pub oversold: bool,
+    /// Set when the ticket is deleted, with the money given back.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub refund: Option<Refund>,
 }
 
-#[derive(Serialize, Deserialize)]
+/// Fare of a ticket, as set when it is issued.
+#[derive(Clone)]
+pub struct Price {
+    pub amount_cents: i64,
+    pub currency: String,
+}
+
+impl Price {
+    pub fn apply_to(&self, ticket: &mut Ticket) {
+        ticket.fare_cents = self.amount_cents;
+        ticket.currency = self.currency.clone();
+    }
+}
+
+/// Money given back to the passenger when a ticket is cancelled.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Refund {
+    pub amount_cents: i64,
+    /// Share of the fare refunded.
+    pub percent: u32,
+    pub reason: String,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
 pub struct Passenger {
     pub ssn: String,
     pub name: String,
@@ -30,143 +87,435 @@ pub struct Passenger {
     pub email: String,
 }
 
-#[async_trait]
-pub trait TicketDatabase {
-    fn ticket_collection(&self) -> Collection<Ticket>;
-    fn deleted_ticket_collection(&self) -> Collection<Ticket>;
+/// Characters of booking references, leaving out the ones easily mistaken for each other.
+const BOOKING_REFERENCE_CHARSET: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
 
-    async fn list_tickets(
-        &self,
-        include_nonvalid: bool,
-        flight_id: Option<&str>,
-    ) -> DbResult<Vec<Ticket>> {
-        let query = match flight_id {
-            Some(flight_id) => doc! { "flight_id": doc! { "$eq": flight_id } },
-            None => doc! {},
-        };
+/// Reservation owning the tickets booked together, possibly for several passengers and flights.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Booking {
+    /// Short locator given to the customer.
+    pub _id: String,
+    pub created_at: DateTime,
+    pub cancelled_at: Option<DateTime>,
+}
 
-        let stream_valid = self.ticket_collection().find(query, None).await?;
-        let mut tickets = stream_valid.collect::<Result<Vec<_>, _>>().await?;
-        if include_nonvalid {
-            let stream_deleted = self.deleted_ticket_collection().find(None, None).await?;
-            let deleted_tickets = stream_deleted.collect::<Result<Vec<_>, _>>().await?;
-            tickets.extend(deleted_tickets);
+impl Booking {
+    pub fn new(locator: String) -> Self {
+        Self {
+            _id: locator,
+            created_at: DateTime::now(),
+            cancelled_at: None,
         }
-        Ok(tickets)
     }
+}
 
-    async fn get_ticket(&self, id: ObjectId, allow_nonvalid: bool) -> DbResult<Ticket> {
-        let ticket = self
-            .ticket_collection()
-            .find_one(doc! { "_id": &id }, None)
-            .await?;
-
-        match ticket {
-            Some(t) => Ok(t),
-            None => {
-                if allow_nonvalid {
-                    let ticket = self
-                        .deleted_ticket_collection()
-                        .find_one(doc! { "_id": &id }, None)
-                        .await?;
-                    return ticket.ok_or_else(|| ApplicationError::not_found("ticket not found"));
-                } else {
-                    return Err(ApplicationError::not_found("ticket not found"));
-                }
-            }
+/// Short locator given to the customer for a booking.
+pub fn new_booking_reference() -> String {
+    let mut rng = rand::thread_rng();
+
+    (0..6)
+        .map(|_| {
+            BOOKING_REFERENCE_CHARSET[rng.gen_range(0..BOOKING_REFERENCE_CHARSET.len())] as char
+        })
+        .collect()
+}
+
+/// Random url through which a ticket can be retrieved.
+pub fn new_ticket_url() -> String {
+    Alphanumeric.sample_string(&mut rand::thread_rng(), 64)
+}
+
+impl Ticket {
+    /// Apply the changes made when the ticket is deleted.
+    pub fn mark_deleted(&mut self, refund: Refund) {
+        self.ticket_status = TicketStatus::Deleted.as_str_name().to_string();
+        self.deleted_at = Some(DateTime::now());
+        self.seat = None;
+        self.version += 1;
+        self.refund = Some(refund);
+    }
+}
+
+/// Passenger waiting for a seat on a sold-out flight.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct WaitlistEntry {
+    pub _id: ObjectId,
+    pub flight_id: String,
+    pub passenger: Passenger,
+    pub estimated_cargo_weight: u32,
+    pub joined_at: DateTime,
+}
+
+impl WaitlistEntry {
+    pub fn new(flight_id: String, passenger: Passenger, estimated_cargo_weight: u32) -> Self {
+        Self {
+            _id: ObjectId::new(),
+            flight_id,
+            passenger,
+            estimated_cargo_weight,
+            joined_at: DateTime::now(),
         }
     }
 
-    async fn get_ticket_from_url(&self, url: String, allow_nonvalid: bool) -> DbResult<Ticket> {
-        let ticket = self
-            .ticket_collection()
-            .find_one(doc! { "url": &url }, None)
-            .await?;
-
-        match ticket {
-            Some(t) => Ok(t),
-            None => {
-                if allow_nonvalid {
-                    let ticket = self
-                        .deleted_ticket_collection()
-                        .find_one(doc! { "url": &url }, None)
-                        .await?;
-                    return ticket.ok_or_else(|| ApplicationError::not_found("ticket not found"));
-                } else {
-                    return Err(ApplicationError::not_found("ticket not found"));
-                }
-            }
+    /// Valid ticket issued to the passenger when a seat frees up, at the given price.
+    pub fn into_ticket(self, price: &Price) -> Ticket {
+        Ticket {
+            _id: ObjectId::new(),
+            url: new_ticket_url(),
+            flight_id: self.flight_id,
+            passenger: self.passenger,
+            reservation_datetime: DateTime::now(),
+            estimated_cargo_weight: self.estimated_cargo_weight,
+            ticket_status: TicketStatus::Valid.as_str_name().to_string(),
+            fare_cents: price.amount_cents,
+            currency: price.currency.clone(),
+            version: 1,
+            booking_reference: None,
+            seat: None,
+            checked_in_at: None,
+            deleted_at: None,
+            oversold: false,
+            refund: None,
         }
     }
+}
 
-    async fn create_ticket(&self, ticket: Ticket) -> DbResult<ObjectId> {
-        let res = self.ticket_collection().insert_one(ticket, None).await?;
-        let id = res.inserted_id.as_object_id().unwrap();
-        Ok(id)
+/// Sales policy of a flight overriding the service defaults.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct FlightPolicy {
+    /// The flight id.
+    pub _id: String,
+    pub overbooking_factor: f64,
+}
+
+/// Ticket change waiting to be published, written atomically with the change itself.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct OutboxEvent {
+    pub _id: ObjectId,
+    pub ticket: Ticket,
+    pub update_kind: UpdateKind,
+    pub created_at: DateTime,
+    pub sent_at: Option<DateTime>,
+    /// Trace context of the request that recorded the event, continued when publishing it.
+    #[serde(default)]
+    pub trace_context: HashMap<String, String>,
+}
+
+impl OutboxEvent {
+    pub fn new(ticket: Ticket, update_kind: UpdateKind) -> Self {
+        Self {
+            _id: ObjectId::new(),
+            ticket,
+            update_kind,
+            created_at: DateTime::now(),
+            sent_at: None,
+            trace_context: telemetry::current_context(),
+        }
     }
+}
 
-    async fn delete_ticket(&self, id: ObjectId) -> DbResult<()> {
-        // retrieve the ticket
-        let mut ticket = self.get_ticket(id, false).await?;
-        // set as invalid
-        ticket.ticket_status = TicketStatus::Deleted.as_str_name().to_string();
-        // insert the ticket in the deleted collection
-        let _ = self
-            .deleted_ticket_collection()
-            .insert_one(ticket, None)
-            .await?;
-        // delete the ticket from the collection
-        self.ticket_collection()
-            .delete_one(doc! { "_id": &id }, None)
-            .await?;
-
-        Ok(())
+/// Idempotency key supplied by a client, remembered with the ticket it created.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct IdempotencyRecord {
+    /// The key itself.
+    pub _id: String,
+    /// Digest of the request the key was first used for.
+    pub fingerprint: String,
+    pub ticket_id: ObjectId,
+    pub expires_at: DateTime,
+}
+
+impl IdempotencyRecord {
+    pub fn new(key: String, fingerprint: String, ticket_id: ObjectId, ttl: Duration) -> Self {
+        Self {
+            _id: key,
+            fingerprint,
+            ticket_id,
+            expires_at: DateTime::from_system_time(SystemTime::now() + ttl),
+        }
     }
+}
+
+/// Broker message already handled, remembered to ignore its redeliveries.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ProcessedMessage {
+    /// Id of the message.
+    pub _id: String,
+    pub expires_at: DateTime,
+}
+
+impl ProcessedMessage {
+    pub fn new(message_id: String, ttl: Duration) -> Self {
+        Self {
+            _id: message_id,
+            expires_at: DateTime::from_system_time(SystemTime::now() + ttl),
+        }
+    }
+}
+
+/// Filters and ordering applied when listing tickets.
+#[derive(Default)]
+pub struct TicketQuery {
+    pub include_nonvalid: bool,
+    pub flight_id: Option<String>,
+    pub booking_reference: Option<String>,
+    pub passenger_surname: Option<String>,
+    pub passenger_email: Option<String>,
+    pub ticket_status: Option<String>,
+    pub reserved_after: Option<DateTime>,
+    pub reserved_before: Option<DateTime>,
+    pub order: TicketOrder,
+}
+
+#[derive(Default, Clone, Copy)]
+pub enum TicketOrder {
+    #[default]
+    ReservationAsc,
+    ReservationDesc,
+}
+
+/// Window of results to return from a listing.
+#[derive(Clone, Copy)]
+pub struct Page {
+    pub skip: u64,
+    pub limit: u64,
+}
+
+/// Storage backend for tickets.
+///
+/// Every implementation must behave the same way, so that the application can be exercised
+/// against the in-memory backend and deployed against MongoDB.
+#[async_trait]
+pub trait TicketDatabase: Send + Sync {
+    /// Check that the backend is reachable.
+    async fn ping(&self) -> DbResult<()>;
+
+    async fn list_tickets(&self, query: &TicketQuery, page: Page) -> DbResult<Vec<Ticket>>;
+
+    /// Same as `list_tickets`, yielding every matching ticket as it is read.
+    async fn stream_tickets(&self, query: &TicketQuery) -> DbResult<TicketStream>;
+
+    async fn get_ticket(&self, id: ObjectId, allow_nonvalid: bool) -> DbResult<Ticket>;
+
+    async fn get_ticket_from_url(&self, url: String, allow_nonvalid: bool) -> DbResult<Ticket>;
+
+    /// Store a new ticket and take one of the `sellable_seats` of its flight, recording a
+    /// `Create` event in the outbox.
+    ///
+    /// Fails with `NoSeatAvailable` if every sellable seat is taken, and with `SeatTaken` if
+    /// the seat of the ticket is assigned to another ticket.
+    ///
+    /// The idempotency record, if any, is stored along with the ticket; fails with
+    /// `IdempotencyKeyInUse` if an unexpired record already exists for the key.
+    async fn create_ticket(
+        &self,
+        ticket: Ticket,
+        sellable_seats: u32,
+        idempotency: Option<IdempotencyRecord>,
+    ) -> DbResult<ObjectId>;
+
+    /// Store a booking with its tickets at once, recording a `Create` event for each ticket.
+    /// `sellable_seats` holds the number of sellable seats of every flight of the tickets.
+    ///
+    /// Either everything is stored or nothing is; fails with `BookingReferenceTaken` if the
+    /// locator is already used, and with `NoSeatAvailable` or `SeatTaken` like
+    /// `create_ticket`.
+    async fn create_booking(
+        &self,
+        booking: Booking,
+        tickets: Vec<Ticket>,
+        sellable_seats: &HashMap<String, u32>,
+    ) -> DbResult<()>;
+
+    async fn get_booking(&self, locator: &str) -> DbResult<Booking>;
+
+    /// Cancel a booking and delete its valid tickets with the refund given for each, freeing
+    /// their seats and recording a `Delete` event for each. Returns the deleted tickets.
+    ///
+    /// Each refund comes with the version of the ticket it was computed from. Fails with
+    /// `ConcurrentWrite` if a ticket to delete has no refund or changed since.
+    async fn cancel_booking(
+        &self,
+        locator: &str,
+        refunds: HashMap<ObjectId, (i64, Refund)>,
+    ) -> DbResult<Vec<Ticket>>;
+
+    /// Unexpired record of an idempotency key.
+    async fn get_idempotency_record(&self, key: &str) -> DbResult<Option<IdempotencyRecord>>;
 
+    /// Mark a ticket as deleted with the refund given and free its seat, recording a `Delete`
+    /// event in the outbox. Returns the deleted ticket.
+    ///
+    /// When `expected_version` is given, fails with `VersionConflict` if the ticket has
+    /// changed since.
+    async fn delete_ticket(
+        &self,
+        id: ObjectId,
+        expected_version: Option<i64>,
+        refund: Refund,
+    ) -> DbResult<Ticket>;
+
+    /// Apply the given paths of `update` to a ticket, recording an `Update` event in the outbox.
+    ///
+    /// Fails with `SeatTaken` if the new seat is assigned to another ticket.
+    ///
+    /// When `expected_version` is given, fails with `VersionConflict` if the ticket has
+    /// changed since.
     async fn update_ticket(
         &self,
         id: ObjectId,
         update: Ticket,
         update_paths: BTreeSet<String>,
-    ) -> DbResult<()> {
-        let mut updated_doc = doc! {};
-
-        let Ticket { passenger, .. } = update;
-
-        for field in update_paths {
-            match field.as_str() {
-                "passenger.ssn" => updated_doc.insert(field, passenger.ssn.clone()),
-                "passenger.name" => updated_doc.insert(field, passenger.name.clone()),
-                "passenger.surname" => updated_doc.insert(field, passenger.surname.clone()),
-                "passenger.birth_date" => updated_doc.insert(field, passenger.birth_date.clone()),
-                "passenger.email" => updated_doc.insert(field, passenger.email.clone()),
-                f => return Err(ApplicationError::invalid_update_path(f.to_string())),
-            };
-        }
+        expected_version: Option<i64>,
+    ) -> DbResult<()>;
 
-        self.ticket_collection()
-            .update_one(doc! { "_id": &id }, doc! { "$set": updated_doc }, None)
-            .await?;
+    /// Move a ticket from status `from` to `to`, recording a `StatusChange` event in the outbox.
+    ///
+    /// Fails with `ConcurrentWrite` if the status is no longer `from`, and with
+    /// `VersionConflict` if `expected_version` is given and the ticket has changed since.
+    async fn update_ticket_status(
+        &self,
+        id: ObjectId,
+        from: TicketStatus,
+        to: TicketStatus,
+        expected_version: Option<i64>,
+    ) -> DbResult<Ticket>;
 
-        Ok(())
-    }
+    /// Check a `Valid` ticket in, recording the time and a `StatusChange` event in the outbox.
+    ///
+    /// Fails like `update_ticket_status`.
+    async fn check_in_ticket(
+        &self,
+        id: ObjectId,
+        expected_version: Option<i64>,
+    ) -> DbResult<Ticket>;
 
-    async fn get_existing_tickets(&self, flight_id: &str) -> DbResult<u32> {
-        let count = self
-            .ticket_collection()
-            .count_documents(doc! { "flight_id": flight_id }, None)
-            .await?;
+    /// Number of tickets holding a seat on the flight.
+    async fn get_existing_tickets(&self, flight_id: &str) -> DbResult<u32>;
 
-        Ok(count.try_into().unwrap())
-    }
+    /// Seats assigned to the valid tickets of the flight.
+    async fn get_taken_seats(&self, flight_id: &str) -> DbResult<Vec<String>>;
+
+    async fn get_flight_policy(&self, flight_id: &str) -> DbResult<Option<FlightPolicy>>;
+
+    /// Create or replace the policy of a flight.
+    async fn set_flight_policy(&self, policy: FlightPolicy) -> DbResult<()>;
+
+    /// Go back to the service defaults for the flight.
+    async fn delete_flight_policy(&self, flight_id: &str) -> DbResult<()>;
+
+    async fn join_waitlist(&self, entry: WaitlistEntry) -> DbResult<()>;
+
+    async fn leave_waitlist(&self, id: ObjectId) -> DbResult<()>;
+
+    /// Waitlist of the flight, first come first served.
+    async fn list_waitlist(&self, flight_id: &str) -> DbResult<Vec<WaitlistEntry>>;
+
+    /// Turn the first entry of the flight's waitlist into a valid ticket, recording a `Create`
+    /// event in the outbox. Returns `None` without changing anything if the waitlist is empty
+    /// or every sellable seat is taken.
+    async fn promote_waitlist_entry(
+        &self,
+        flight_id: &str,
+        sellable_seats: u32,
+        price: &Price,
+    ) -> DbResult<Option<Ticket>>;
+
+    /// Mark the valid and checked-in tickets of a cancelled flight as `FlightCancelled`,
+    /// recording a `StatusChange` event for each, and clear the waitlist of the flight.
+    /// Returns the changed tickets, or `None` without changing anything if the message was
+    /// already processed.
+    async fn cancel_flight_tickets(
+        &self,
+        flight_id: &str,
+        message: ProcessedMessage,
+    ) -> DbResult<Option<Vec<Ticket>>>;
+
+    /// Flag the tickets of the flight beyond its sellable seats as oversold, the earliest
+    /// reservations keeping their seats, and clear the flag on the others, recording an
+    /// `Update` event for each changed ticket. Returns the changed tickets, or `None` without
+    /// changing anything if the message was already processed.
+    async fn flag_oversold_tickets(
+        &self,
+        flight_id: &str,
+        sellable_seats: u32,
+        message: ProcessedMessage,
+    ) -> DbResult<Option<Vec<Ticket>>>;
+
+    /// Oldest events of the outbox that were not published yet.
+    async fn pending_events(&self, limit: u64) -> DbResult<Vec<OutboxEvent>>;
+
+    async fn mark_event_sent(&self, id: ObjectId) -> DbResult<()>;
 }
 
-impl TicketDatabase for Database {
-    fn ticket_collection(&self) -> Collection<Ticket> {
-        self.collection("tickets")
+#[cfg(test)]
+pub mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    /// Valid ticket for the flight, as issued by `CreateTicket`.
+    pub fn new_ticket(flight_id: &str) -> Ticket {
+        let passenger = Passenger {
+            ssn: "RSSMRA80A01H501U".to_string(),
+            name: "Mario".to_string(),
+            surname: "Rossi".to_string(),
+            birth_date: DateTime::from_millis(315_532_800_000),
+            email: "mario.rossi@example.com".to_string(),
+        };
+        let price = Price {
+            amount_cents: 10_000,
+            currency: "EUR".to_string(),
+        };
+        WaitlistEntry::new(flight_id.to_string(), passenger, 20).into_ticket(&price)
+    }
+
+    /// Send `requests` ticket creations for the flight at once, returns how many succeeded.
+    pub async fn create_in_parallel(
+        db: Arc<dyn TicketDatabase>,
+        flight_id: &str,
+        sellable_seats: u32,
+        requests: usize,
+    ) -> u32 {
+        let tasks: Vec<_> = (0..requests)
+            .map(|_| {
+                let db = db.clone();
+                let ticket = new_ticket(flight_id);
+                tokio::spawn(async move { db.create_ticket(ticket, sellable_seats, None).await })
+            })
+            .collect();
+
+        let mut created = 0;
+        for task in tasks {
+            match task.await.unwrap() {
+                Ok(_) => created += 1,
+                Err(ApplicationError::NoSeatAvailable) => {}
+                Err(e) => panic!("unexpected error: {e}"),
+            }
+        }
+        created
     }
 
-    fn deleted_ticket_collection(&self) -> Collection<Ticket> {
-        self.collection("tickets-deleted")
+    /// Sell every seat of the flight, then refund the first ticket and delete it.
+    pub async fn refund_and_delete(db: &dyn TicketDatabase, flight_id: &str, sellable_seats: u32) {
+        let mut ids = vec![];
+        for _ in 0..sellable_seats {
+            let ticket = new_ticket(flight_id);
+            let id = db
+                .create_ticket(ticket, sellable_seats, None)
+                .await
+                .unwrap();
+            ids.push(id);
+        }
+
+        let refund = Refund {
+            amount_cents: 0,
+            percent: 0,
+            reason: "ticket already refunded".to_string(),
+        };
+        db.update_ticket_status(ids[0], TicketStatus::Valid, TicketStatus::Refunded, None)
+            .await
+            .unwrap();
+        db.delete_ticket(ids[0], None, refund).await.unwrap();
     }
 }
@@ -0,0 +1,73 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use prost::Message;
+use tonic::async_trait;
+
+use crate::dependencies::FlightManager;
+use crate::proto::flightmngr::{Flight, Plane};
+use crate::rabbitmq::{HandleError, MessageHandler};
+
+use super::data::{ProcessedMessage, TicketDatabase};
+use super::{is_cancelled, sellable_seats};
+
+/// How long a handled message is remembered, far beyond any redelivery.
+const PROCESSED_MESSAGE_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Applies the flight updates published by flightmngr to the tickets of the flight.
+///
+/// A cancelled flight cancels its tickets and clears its waitlist, while any other update may
+/// come with another aircraft, so the tickets no longer fitting in it are flagged as oversold.
+pub struct FlightEventHandler {
+    db: Arc<dyn TicketDatabase>,
+    flightmngr: FlightManager,
+    overbooking_factor: f64,
+}
+
+impl FlightEventHandler {
+    pub fn new(
+        db: Arc<dyn TicketDatabase>,
+        flightmngr: FlightManager,
+        overbooking_factor: f64,
+    ) -> Self {
+        Self {
+            db,
+            flightmngr,
+            overbooking_factor,
+        }
+    }
+}
+
+#[async_trait]
+impl MessageHandler for FlightEventHandler {
+    async fn handle(&self, message_id: &str, content: &[u8]) -> Result<(), HandleError> {
+        let flight = Flight::decode(content).map_err(|e| HandleError::Malformed(e.to_string()))?;
+        let message = ProcessedMessage::new(message_id.to_string(), PROCESSED_MESSAGE_TTL);
+
+        let changed = if is_cancelled(&flight) {
+            self.db.cancel_flight_tickets(&flight.id, message).await?
+        } else {
+            let Plane { cabin_capacity, .. } = self.flightmngr.get_plane(flight.plane_id).await?;
+            let sellable_seats = sellable_seats(
+                self.db.as_ref(),
+                self.overbooking_factor,
+                &flight.id,
+                cabin_capacity,
+            )
+            .await?;
+            self.db
+                .flag_oversold_tickets(&flight.id, sellable_seats, message)
+                .await?
+        };
+
+        match changed {
+            Some(tickets) => tracing::info!(
+                flight_id = %flight.id,
+                changed_tickets = tickets.len(),
+                "applied flight update"
+            ),
+            None => tracing::debug!(message_id, "flight update already applied"),
+        }
+        Ok(())
+    }
+}
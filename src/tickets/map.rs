@@ -2,15 +2,14 @@ use mongodb::bson::oid::ObjectId;
 use tonic::Status;
 
 use crate::datautils::{convert_datetime_to_timestamp, convert_timestamp_to_datetime};
-use crate::proto::ticketsrvc::{self, TicketStatus};
+use crate::proto::ticketsrvc::{self, ListTicketsOrder, TicketStatus};
 
-use super::data;
+use super::{data, status};
 
 impl From<data::Ticket> for ticketsrvc::Ticket {
     fn from(t: data::Ticket) -> Self {
         let p = t.passenger;
-        let ticket_status =
-            TicketStatus::from_str_name(&t.ticket_status).unwrap_or_default() as i32;
+        let ticket_status = status::parse_status(&t.ticket_status) as i32;
 
         Self {
             id: t._id.to_string(),
@@ -26,6 +25,14 @@ impl From<data::Ticket> for ticketsrvc::Ticket {
             reservation_datetime: convert_datetime_to_timestamp(t.reservation_datetime),
             estimated_cargo_weight: t.estimated_cargo_weight,
             ticket_status,
+            fare_cents: t.fare_cents,
+            currency: t.currency,
+            version: t.version,
+            checked_in_at: t.checked_in_at.and_then(convert_datetime_to_timestamp),
+            seat: t.seat,
+            booking_reference: t.booking_reference.unwrap_or_default(),
+            oversold: t.oversold,
+            refund: t.refund.map(Into::into),
         }
     }
 }
@@ -37,6 +44,7 @@ impl TryFrom<ticketsrvc::Ticket> for data::Ticket {
         let Some(p) = t.passenger else {
             return Err(Status::invalid_argument("missing passenger details"));
         };
+        let passenger = p.try_into()?;
 
         let _id = ObjectId::new();
 
@@ -49,16 +57,117 @@ impl TryFrom<ticketsrvc::Ticket> for data::Ticket {
             _id,
             url: t.url,
             flight_id: t.flight_id,
-            passenger: data::Passenger {
+            passenger,
+            reservation_datetime: convert_timestamp_to_datetime(t.reservation_datetime)?,
+            estimated_cargo_weight: t.estimated_cargo_weight,
+            ticket_status,
+            // priced by the service
+            fare_cents: 0,
+            currency: String::new(),
+            version: t.version,
+            seat: t.seat.filter(|s| !s.is_empty()),
+            // assigned by the service
+            booking_reference: None,
+            checked_in_at: None,
+            deleted_at: None,
+            oversold: false,
+            refund: None,
+        })
+    }
+}
+
+impl From<data::Refund> for ticketsrvc::Refund {
+    fn from(r: data::Refund) -> Self {
+        Self {
+            amount_cents: r.amount_cents,
+            percent: r.percent,
+            reason: r.reason,
+        }
+    }
+}
+
+impl From<(data::Booking, Vec<data::Ticket>)> for ticketsrvc::Booking {
+    fn from((b, tickets): (data::Booking, Vec<data::Ticket>)) -> Self {
+        Self {
+            booking_reference: b._id,
+            tickets: tickets.into_iter().map(Into::into).collect(),
+            created_at: convert_datetime_to_timestamp(b.created_at),
+            cancelled_at: b.cancelled_at.and_then(convert_datetime_to_timestamp),
+        }
+    }
+}
+
+impl TryFrom<ticketsrvc::PassengerDetails> for data::Passenger {
+    type Error = Status;
+
+    fn try_from(p: ticketsrvc::PassengerDetails) -> Result<Self, Self::Error> {
+        Ok(Self {
+            ssn: p.ssn,
+            name: p.name,
+            surname: p.surname,
+            birth_date: convert_timestamp_to_datetime(p.birth_date)?,
+            email: p.email,
+        })
+    }
+}
+
+impl From<data::WaitlistEntry> for ticketsrvc::WaitlistEntry {
+    fn from(e: data::WaitlistEntry) -> Self {
+        let p = e.passenger;
+
+        Self {
+            id: e._id.to_string(),
+            flight_id: e.flight_id,
+            passenger: Some(ticketsrvc::PassengerDetails {
                 ssn: p.ssn,
                 name: p.name,
                 surname: p.surname,
-                birth_date: convert_timestamp_to_datetime(p.birth_date)?,
+                birth_date: convert_datetime_to_timestamp(p.birth_date),
                 email: p.email,
-            },
-            reservation_datetime: convert_timestamp_to_datetime(t.reservation_datetime)?,
-            estimated_cargo_weight: t.estimated_cargo_weight,
+            }),
+            estimated_cargo_weight: e.estimated_cargo_weight,
+            joined_at: convert_datetime_to_timestamp(e.joined_at),
+        }
+    }
+}
+
+impl TryFrom<&ticketsrvc::ListTicketsRequest> for data::TicketQuery {
+    type Error = Status;
+
+    fn try_from(r: &ticketsrvc::ListTicketsRequest) -> Result<Self, Self::Error> {
+        let ticket_status = r
+            .status
+            .map(|s| {
+                TicketStatus::try_from(s)
+                    .map(|s| s.as_str_name().to_string())
+                    .or(Err(Status::invalid_argument("invalid ticket status")))
+            })
+            .transpose()?;
+
+        let order = match ListTicketsOrder::try_from(r.order_by) {
+            Ok(ListTicketsOrder::ReservationDatetimeAsc) => data::TicketOrder::ReservationAsc,
+            Ok(ListTicketsOrder::ReservationDatetimeDesc) => data::TicketOrder::ReservationDesc,
+            Err(_) => return Err(Status::invalid_argument("invalid order")),
+        };
+
+        Ok(Self {
+            include_nonvalid: r.include_nonvalid,
+            flight_id: r.flight_id.clone(),
+            booking_reference: None,
+            passenger_surname: r.passenger_surname.clone(),
+            passenger_email: r.passenger_email.clone(),
             ticket_status,
+            reserved_after: r
+                .reserved_after
+                .clone()
+                .map(|t| convert_timestamp_to_datetime(Some(t)))
+                .transpose()?,
+            reserved_before: r
+                .reserved_before
+                .clone()
+                .map(|t| convert_timestamp_to_datetime(Some(t)))
+                .transpose()?,
+            order,
         })
     }
 }
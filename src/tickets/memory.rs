@@ -0,0 +1,714 @@
+use mongodb::bson::{oid::ObjectId, DateTime};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::Mutex;
+use tonic::async_trait;
+
+use crate::errors::ApplicationError;
+use crate::proto::ticketsrvc::TicketStatus;
+use crate::rabbitmq::UpdateKind;
+
+use super::data::{
+    Booking, DbResult, FlightPolicy, IdempotencyRecord, OutboxEvent, Page, Price, ProcessedMessage,
+    Refund, Ticket, TicketDatabase, TicketOrder, TicketQuery, TicketStream, WaitlistEntry,
+};
+use super::status;
+
+/// Ticket storage kept in process memory, intended for tests and local development.
+#[derive(Default)]
+pub struct MemoryDatabase {
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    tickets: BTreeMap<ObjectId, Ticket>,
+    flight_seats: HashMap<String, u32>,
+    outbox: BTreeMap<ObjectId, OutboxEvent>,
+    idempotency_records: HashMap<String, IdempotencyRecord>,
+    waitlist: BTreeMap<ObjectId, WaitlistEntry>,
+    flight_policies: HashMap<String, FlightPolicy>,
+    bookings: HashMap<String, Booking>,
+    processed_messages: HashMap<String, ProcessedMessage>,
+}
+
+impl State {
+    fn push_event(&mut self, ticket: Ticket, update_kind: UpdateKind) {
+        let event = OutboxEvent::new(ticket, update_kind);
+        self.outbox.insert(event._id, event);
+    }
+
+    fn valid_ticket_mut(&mut self, id: ObjectId) -> Option<&mut Ticket> {
+        self.tickets.get_mut(&id).filter(|t| t.deleted_at.is_none())
+    }
+
+    /// Move a ticket from status `from` to `to`, applying the other changes given, and give
+    /// its seat back if the new status does not hold one.
+    fn set_ticket_status(
+        &mut self,
+        id: ObjectId,
+        from: TicketStatus,
+        to: TicketStatus,
+        expected_version: Option<i64>,
+        apply: impl FnOnce(&mut Ticket),
+    ) -> DbResult<Ticket> {
+        let ticket = self
+            .valid_ticket_mut(id)
+            .ok_or_else(|| ApplicationError::not_found("ticket not found"))?;
+        check_version(ticket, expected_version)?;
+        if ticket.ticket_status != from.as_str_name() {
+            return Err(ApplicationError::ConcurrentWrite);
+        }
+
+        ticket.ticket_status = to.as_str_name().to_string();
+        ticket.version += 1;
+        apply(ticket);
+        let releases_seat = status::releases_seat(from, to);
+        if releases_seat {
+            ticket.seat = None;
+        }
+
+        let ticket = ticket.clone();
+        if releases_seat {
+            self.release_seats(&ticket.flight_id, 1);
+        }
+        self.push_event(ticket.clone(), UpdateKind::StatusChange);
+
+        Ok(ticket)
+    }
+
+    fn idempotency_record(&self, key: &str) -> Option<&IdempotencyRecord> {
+        self.idempotency_records
+            .get(key)
+            .filter(|r| r.expires_at > DateTime::now())
+    }
+
+    /// Remember the message as processed, false if it already was.
+    fn record_processed(&mut self, message: ProcessedMessage) -> bool {
+        let processed = self
+            .processed_messages
+            .get(&message._id)
+            .is_some_and(|m| m.expires_at > DateTime::now());
+        if !processed {
+            self.processed_messages.insert(message._id.clone(), message);
+        }
+        !processed
+    }
+
+    /// Tickets of the flight whose passenger still expects to fly, earliest reservation first.
+    fn active_tickets_mut(&mut self, flight_id: &str) -> Vec<&mut Ticket> {
+        let mut tickets: Vec<_> = self
+            .tickets
+            .values_mut()
+            .filter(|t| {
+                t.flight_id == flight_id
+                    && t.deleted_at.is_none()
+                    && status::ACTIVE.contains(&status::parse_status(&t.ticket_status))
+            })
+            .collect();
+        tickets.sort_by_key(|t| (t.reservation_datetime, t._id));
+        tickets
+    }
+
+    fn seat_taken(&self, flight_id: &str, seat: &str, by_other_than: ObjectId) -> bool {
+        self.tickets.values().any(|t| {
+            t._id != by_other_than
+                && t.flight_id == flight_id
+                && t.deleted_at.is_none()
+                && t.seat.as_deref() == Some(seat)
+        })
+    }
+
+    fn existing_tickets(&self, flight_id: &str) -> u32 {
+        self.tickets
+            .values()
+            .filter(|t| {
+                t.flight_id == flight_id
+                    && t.deleted_at.is_none()
+                    && status::holds_seat(&t.ticket_status)
+            })
+            .count()
+            .try_into()
+            .unwrap()
+    }
+
+    /// Whether `count` more seats of the flight fit within `sellable_seats`.
+    fn seats_left(&mut self, flight_id: &str, sellable_seats: u32, count: u32) -> bool {
+        let existing_tickets = self.existing_tickets(flight_id);
+        let reserved = self
+            .flight_seats
+            .entry(flight_id.to_string())
+            .or_insert(existing_tickets);
+        *reserved + count <= sellable_seats
+    }
+
+    fn reserve_seats(&mut self, flight_id: &str, count: u32) {
+        *self.flight_seats.entry(flight_id.to_string()).or_default() += count;
+    }
+
+    fn release_seats(&mut self, flight_id: &str, count: u32) {
+        if let Some(reserved) = self.flight_seats.get_mut(flight_id) {
+            *reserved = reserved.saturating_sub(count);
+        }
+    }
+}
+
+fn matches_query(ticket: &Ticket, query: &TicketQuery) -> bool {
+    (query.include_nonvalid || ticket.deleted_at.is_none())
+        && query
+            .flight_id
+            .as_ref()
+            .map_or(true, |f| &ticket.flight_id == f)
+        && query
+            .booking_reference
+            .as_ref()
+            .map_or(true, |b| ticket.booking_reference.as_ref() == Some(b))
+        && query
+            .passenger_surname
+            .as_ref()
+            .map_or(true, |s| &ticket.passenger.surname == s)
+        && query
+            .passenger_email
+            .as_ref()
+            .map_or(true, |e| &ticket.passenger.email == e)
+        && query
+            .ticket_status
+            .as_ref()
+            .map_or(true, |s| &ticket.ticket_status == s)
+        && query
+            .reserved_after
+            .map_or(true, |d| ticket.reservation_datetime >= d)
+        && query
+            .reserved_before
+            .map_or(true, |d| ticket.reservation_datetime < d)
+}
+
+fn check_version(ticket: &Ticket, expected_version: Option<i64>) -> DbResult<()> {
+    match expected_version {
+        Some(expected) if ticket.version != expected => {
+            Err(ApplicationError::version_conflict(expected))
+        }
+        _ => Ok(()),
+    }
+}
+
+#[async_trait]
+impl TicketDatabase for MemoryDatabase {
+    async fn ping(&self) -> DbResult<()> {
+        Ok(())
+    }
+
+    async fn list_tickets(&self, query: &TicketQuery, page: Page) -> DbResult<Vec<Ticket>> {
+        let state = self.state.lock().unwrap();
+
+        let mut tickets: Vec<_> = state
+            .tickets
+            .values()
+            .filter(|t| matches_query(t, query))
+            .cloned()
+            .collect();
+
+        tickets.sort_by_key(|t| (t.reservation_datetime, t._id));
+        if let TicketOrder::ReservationDesc = query.order {
+            tickets.reverse();
+        }
+
+        Ok(tickets
+            .into_iter()
+            .skip(page.skip as usize)
+            .take(page.limit as usize)
+            .collect())
+    }
+
+    async fn stream_tickets(&self, query: &TicketQuery) -> DbResult<TicketStream> {
+        let page = Page {
+            skip: 0,
+            limit: u64::MAX,
+        };
+        let tickets = self.list_tickets(query, page).await?;
+
+        Ok(Box::pin(tokio_stream::iter(tickets.into_iter().map(Ok))))
+    }
+
+    async fn get_ticket(&self, id: ObjectId, allow_nonvalid: bool) -> DbResult<Ticket> {
+        let state = self.state.lock().unwrap();
+
+        state
+            .tickets
+            .get(&id)
+            .filter(|t| allow_nonvalid || t.deleted_at.is_none())
+            .cloned()
+            .ok_or_else(|| ApplicationError::not_found("ticket not found"))
+    }
+
+    async fn get_ticket_from_url(&self, url: String, allow_nonvalid: bool) -> DbResult<Ticket> {
+        let state = self.state.lock().unwrap();
+
+        state
+            .tickets
+            .values()
+            .find(|t| t.url == url && (allow_nonvalid || t.deleted_at.is_none()))
+            .cloned()
+            .ok_or_else(|| ApplicationError::not_found("ticket not found"))
+    }
+
+    async fn create_ticket(
+        &self,
+        ticket: Ticket,
+        sellable_seats: u32,
+        idempotency: Option<IdempotencyRecord>,
+    ) -> DbResult<ObjectId> {
+        let mut state = self.state.lock().unwrap();
+
+        if !state.seats_left(&ticket.flight_id, sellable_seats, 1) {
+            return Err(ApplicationError::NoSeatAvailable);
+        }
+        if let Some(seat) = &ticket.seat {
+            if state.seat_taken(&ticket.flight_id, seat, ticket._id) {
+                return Err(ApplicationError::SeatTaken);
+            }
+        }
+
+        if let Some(record) = idempotency {
+            if state.idempotency_record(&record._id).is_some() {
+                return Err(ApplicationError::IdempotencyKeyInUse);
+            }
+            state.idempotency_records.insert(record._id.clone(), record);
+        }
+
+        let id = ticket._id;
+        state.reserve_seats(&ticket.flight_id, 1);
+        state.tickets.insert(id, ticket.clone());
+        state.push_event(ticket, UpdateKind::Create);
+        Ok(id)
+    }
+
+    async fn create_booking(
+        &self,
+        booking: Booking,
+        tickets: Vec<Ticket>,
+        sellable_seats: &HashMap<String, u32>,
+    ) -> DbResult<()> {
+        let mut state = self.state.lock().unwrap();
+
+        if state.bookings.contains_key(&booking._id) {
+            return Err(ApplicationError::BookingReferenceTaken);
+        }
+
+        let mut counts: HashMap<&str, u32> = HashMap::new();
+        for ticket in &tickets {
+            *counts.entry(&ticket.flight_id).or_default() += 1;
+        }
+        for (&flight_id, &count) in &counts {
+            let sellable_seats = sellable_seats.get(flight_id).copied().unwrap_or_default();
+            if !state.seats_left(flight_id, sellable_seats, count) {
+                return Err(ApplicationError::NoSeatAvailable);
+            }
+        }
+
+        for (i, ticket) in tickets.iter().enumerate() {
+            let Some(seat) = &ticket.seat else {
+                continue;
+            };
+            let taken_in_group = tickets[..i].iter().any(|t| t.seat.as_ref() == Some(seat));
+            if taken_in_group || state.seat_taken(&ticket.flight_id, seat, ticket._id) {
+                return Err(ApplicationError::SeatTaken);
+            }
+        }
+
+        state.bookings.insert(booking._id.clone(), booking);
+        for (flight_id, count) in counts {
+            state.reserve_seats(flight_id, count);
+        }
+        for ticket in tickets {
+            state.tickets.insert(ticket._id, ticket.clone());
+            state.push_event(ticket, UpdateKind::Create);
+        }
+        Ok(())
+    }
+
+    async fn get_booking(&self, locator: &str) -> DbResult<Booking> {
+        let state = self.state.lock().unwrap();
+
+        state
+            .bookings
+            .get(locator)
+            .cloned()
+            .ok_or_else(|| ApplicationError::not_found("booking not found"))
+    }
+
+    async fn cancel_booking(
+        &self,
+        locator: &str,
+        mut refunds: HashMap<ObjectId, (i64, Refund)>,
+    ) -> DbResult<Vec<Ticket>> {
+        let mut state = self.state.lock().unwrap();
+
+        let booking = state
+            .bookings
+            .get(locator)
+            .ok_or_else(|| ApplicationError::not_found("booking not found"))?;
+        if booking.cancelled_at.is_some() {
+            return Err(ApplicationError::BookingCancelled);
+        }
+
+        let in_booking =
+            |t: &Ticket| t.booking_reference.as_deref() == Some(locator) && t.deleted_at.is_none();
+        if state
+            .tickets
+            .values()
+            .any(|t| in_booking(t) && refunds.get(&t._id).map(|(v, _)| *v) != Some(t.version))
+        {
+            return Err(ApplicationError::ConcurrentWrite);
+        }
+
+        if let Some(booking) = state.bookings.get_mut(locator) {
+            booking.cancelled_at = Some(DateTime::now());
+        }
+
+        let mut deleted = vec![];
+        let mut released = vec![];
+        for ticket in state.tickets.values_mut().filter(|t| in_booking(t)) {
+            if status::holds_seat(&ticket.ticket_status) {
+                released.push(ticket.flight_id.clone());
+            }
+            let (_, refund) = refunds.remove(&ticket._id).unwrap();
+            ticket.mark_deleted(refund);
+            deleted.push(ticket.clone());
+        }
+        for flight_id in released {
+            state.release_seats(&flight_id, 1);
+        }
+        for ticket in &deleted {
+            state.push_event(ticket.clone(), UpdateKind::Delete);
+        }
+
+        Ok(deleted)
+    }
+
+    async fn get_idempotency_record(&self, key: &str) -> DbResult<Option<IdempotencyRecord>> {
+        let state = self.state.lock().unwrap();
+
+        Ok(state.idempotency_record(key).cloned())
+    }
+
+    async fn delete_ticket(
+        &self,
+        id: ObjectId,
+        expected_version: Option<i64>,
+        refund: Refund,
+    ) -> DbResult<Ticket> {
+        let mut state = self.state.lock().unwrap();
+
+        let ticket = state
+            .valid_ticket_mut(id)
+            .ok_or_else(|| ApplicationError::not_found("ticket not found"))?;
+        check_version(ticket, expected_version)?;
+
+        // a no-show or refunded ticket already gave its seat back
+        let holds_seat = status::holds_seat(&ticket.ticket_status);
+        ticket.mark_deleted(refund);
+
+        let ticket = ticket.clone();
+        if holds_seat {
+            state.release_seats(&ticket.flight_id, 1);
+        }
+        state.push_event(ticket.clone(), UpdateKind::Delete);
+
+        Ok(ticket)
+    }
+
+    async fn update_ticket(
+        &self,
+        id: ObjectId,
+        update: Ticket,
+        update_paths: BTreeSet<String>,
+        expected_version: Option<i64>,
+    ) -> DbResult<()> {
+        let mut state = self.state.lock().unwrap();
+
+        // validate every path before touching the stored ticket
+        if let Some(f) = update_paths.iter().find(|f| {
+            !matches!(
+                f.as_str(),
+                "passenger.ssn"
+                    | "passenger.name"
+                    | "passenger.surname"
+                    | "passenger.birth_date"
+                    | "passenger.email"
+                    | "seat"
+            )
+        }) {
+            return Err(ApplicationError::invalid_update_path(f.to_string()));
+        }
+
+        let Ticket {
+            passenger, seat, ..
+        } = update;
+
+        let ticket = state
+            .tickets
+            .get(&id)
+            .filter(|t| t.deleted_at.is_none())
+            .ok_or_else(|| ApplicationError::not_found("ticket not found"))?;
+        check_version(ticket, expected_version)?;
+        if let (true, Some(seat)) = (update_paths.contains("seat"), &seat) {
+            if state.seat_taken(&ticket.flight_id, seat, id) {
+                return Err(ApplicationError::SeatTaken);
+            }
+        }
+
+        let ticket = state.valid_ticket_mut(id).unwrap();
+
+        for field in update_paths {
+            match field.as_str() {
+                "passenger.ssn" => ticket.passenger.ssn = passenger.ssn.clone(),
+                "passenger.name" => ticket.passenger.name = passenger.name.clone(),
+                "passenger.surname" => ticket.passenger.surname = passenger.surname.clone(),
+                "passenger.birth_date" => ticket.passenger.birth_date = passenger.birth_date,
+                "passenger.email" => ticket.passenger.email = passenger.email.clone(),
+                "seat" => ticket.seat = seat.clone(),
+                _ => unreachable!(),
+            };
+        }
+
+        ticket.version += 1;
+
+        let ticket = ticket.clone();
+        state.push_event(ticket, UpdateKind::Update);
+
+        Ok(())
+    }
+
+    async fn get_taken_seats(&self, flight_id: &str) -> DbResult<Vec<String>> {
+        let state = self.state.lock().unwrap();
+
+        Ok(state
+            .tickets
+            .values()
+            .filter(|t| t.flight_id == flight_id && t.deleted_at.is_none())
+            .filter_map(|t| t.seat.clone())
+            .collect())
+    }
+
+    async fn update_ticket_status(
+        &self,
+        id: ObjectId,
+        from: TicketStatus,
+        to: TicketStatus,
+        expected_version: Option<i64>,
+    ) -> DbResult<Ticket> {
+        let mut state = self.state.lock().unwrap();
+
+        state.set_ticket_status(id, from, to, expected_version, |t| {
+            // back to before the check-in
+            if to == TicketStatus::Valid {
+                t.checked_in_at = None;
+            }
+        })
+    }
+
+    async fn check_in_ticket(
+        &self,
+        id: ObjectId,
+        expected_version: Option<i64>,
+    ) -> DbResult<Ticket> {
+        let mut state = self.state.lock().unwrap();
+
+        state.set_ticket_status(
+            id,
+            TicketStatus::Valid,
+            TicketStatus::CheckedIn,
+            expected_version,
+            |t| t.checked_in_at = Some(DateTime::now()),
+        )
+    }
+
+    async fn get_existing_tickets(&self, flight_id: &str) -> DbResult<u32> {
+        let state = self.state.lock().unwrap();
+
+        Ok(state.existing_tickets(flight_id))
+    }
+
+    async fn get_flight_policy(&self, flight_id: &str) -> DbResult<Option<FlightPolicy>> {
+        let state = self.state.lock().unwrap();
+
+        Ok(state.flight_policies.get(flight_id).cloned())
+    }
+
+    async fn set_flight_policy(&self, policy: FlightPolicy) -> DbResult<()> {
+        let mut state = self.state.lock().unwrap();
+
+        state.flight_policies.insert(policy._id.clone(), policy);
+        Ok(())
+    }
+
+    async fn delete_flight_policy(&self, flight_id: &str) -> DbResult<()> {
+        let mut state = self.state.lock().unwrap();
+
+        state.flight_policies.remove(flight_id);
+        Ok(())
+    }
+
+    async fn join_waitlist(&self, entry: WaitlistEntry) -> DbResult<()> {
+        let mut state = self.state.lock().unwrap();
+
+        state.waitlist.insert(entry._id, entry);
+        Ok(())
+    }
+
+    async fn leave_waitlist(&self, id: ObjectId) -> DbResult<()> {
+        let mut state = self.state.lock().unwrap();
+
+        state
+            .waitlist
+            .remove(&id)
+            .map(|_| ())
+            .ok_or_else(|| ApplicationError::not_found("waitlist entry not found"))
+    }
+
+    async fn list_waitlist(&self, flight_id: &str) -> DbResult<Vec<WaitlistEntry>> {
+        let state = self.state.lock().unwrap();
+
+        let mut entries: Vec<_> = state
+            .waitlist
+            .values()
+            .filter(|e| e.flight_id == flight_id)
+            .cloned()
+            .collect();
+        entries.sort_by_key(|e| (e.joined_at, e._id));
+        Ok(entries)
+    }
+
+    async fn promote_waitlist_entry(
+        &self,
+        flight_id: &str,
+        sellable_seats: u32,
+        price: &Price,
+    ) -> DbResult<Option<Ticket>> {
+        let mut state = self.state.lock().unwrap();
+
+        if !state.seats_left(flight_id, sellable_seats, 1) {
+            return Ok(None);
+        }
+        let first = state
+            .waitlist
+            .values()
+            .filter(|e| e.flight_id == flight_id)
+            .min_by_key(|e| (e.joined_at, e._id))
+            .map(|e| e._id);
+        let Some(entry) = first.and_then(|id| state.waitlist.remove(&id)) else {
+            return Ok(None);
+        };
+
+        let ticket = entry.into_ticket(price);
+        state.reserve_seats(flight_id, 1);
+        state.tickets.insert(ticket._id, ticket.clone());
+        state.push_event(ticket.clone(), UpdateKind::Create);
+        Ok(Some(ticket))
+    }
+
+    async fn cancel_flight_tickets(
+        &self,
+        flight_id: &str,
+        message: ProcessedMessage,
+    ) -> DbResult<Option<Vec<Ticket>>> {
+        let mut state = self.state.lock().unwrap();
+
+        if !state.record_processed(message) {
+            return Ok(None);
+        }
+
+        state.waitlist.retain(|_, e| e.flight_id != flight_id);
+
+        let mut cancelled = vec![];
+        for ticket in state.active_tickets_mut(flight_id) {
+            ticket.ticket_status = TicketStatus::FlightCancelled.as_str_name().to_string();
+            ticket.version += 1;
+            cancelled.push(ticket.clone());
+        }
+        for ticket in &cancelled {
+            state.push_event(ticket.clone(), UpdateKind::StatusChange);
+        }
+
+        Ok(Some(cancelled))
+    }
+
+    async fn flag_oversold_tickets(
+        &self,
+        flight_id: &str,
+        sellable_seats: u32,
+        message: ProcessedMessage,
+    ) -> DbResult<Option<Vec<Ticket>>> {
+        let mut state = self.state.lock().unwrap();
+
+        if !state.record_processed(message) {
+            return Ok(None);
+        }
+
+        let mut changed = vec![];
+        for (i, ticket) in state.active_tickets_mut(flight_id).into_iter().enumerate() {
+            let oversold = i >= sellable_seats as usize;
+            if ticket.oversold != oversold {
+                ticket.oversold = oversold;
+                ticket.version += 1;
+                changed.push(ticket.clone());
+            }
+        }
+        for ticket in &changed {
+            state.push_event(ticket.clone(), UpdateKind::Update);
+        }
+
+        Ok(Some(changed))
+    }
+
+    async fn pending_events(&self, limit: u64) -> DbResult<Vec<OutboxEvent>> {
+        let state = self.state.lock().unwrap();
+
+        Ok(state
+            .outbox
+            .values()
+            .filter(|e| e.sent_at.is_none())
+            .take(limit as usize)
+            .cloned()
+            .collect())
+    }
+
+    async fn mark_event_sent(&self, id: ObjectId) -> DbResult<()> {
+        let mut state = self.state.lock().unwrap();
+
+        if let Some(event) = state.outbox.get_mut(&id) {
+            event.sent_at = Some(DateTime::now());
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::tickets::data::tests::{create_in_parallel, refund_and_delete};
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn parallel_creates_fill_exactly_the_sellable_seats() {
+        let db = Arc::new(MemoryDatabase::default());
+
+        let created = create_in_parallel(db.clone(), "AZ610", 7, 20).await;
+
+        assert_eq!(created, 7);
+        assert_eq!(db.get_existing_tickets("AZ610").await.unwrap(), 7);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn deleting_a_refunded_ticket_frees_its_seat_once() {
+        let db = Arc::new(MemoryDatabase::default());
+
+        refund_and_delete(db.as_ref(), "AZ610", 3).await;
+        let created = create_in_parallel(db.clone(), "AZ610", 3, 5).await;
+
+        assert_eq!(created, 1);
+        assert_eq!(db.get_existing_tickets("AZ610").await.unwrap(), 3);
+    }
+}
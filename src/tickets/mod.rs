@@ -1,30 +1,63 @@
-use mongodb::Database;
-use rand::distributions::{Alphanumeric, DistString};
+use std::collections::{HashMap, HashSet};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+use mongodb::bson::DateTime;
+use tokio_stream::{Stream, StreamExt};
 use tonic::{Request, Response, Status};
 
-use crate::datautils::convert_str_to_object_id;
+use crate::datautils::{convert_str_to_object_id, convert_timestamp_to_datetime};
 use crate::dependencies::{FlightManager, ValidationService};
+use crate::errors::ApplicationError;
+use crate::idempotency::{fingerprint, idempotency_key};
+use crate::pagination::{parse_page, query_digest, PageToken};
 use crate::parse::parse_update_paths;
-use crate::proto::flightmngr::Plane;
+use crate::proto::flightmngr::{Flight, FlightStatus, Plane};
 use crate::proto::ticketsrvc::get_ticket_request::Query;
 use crate::proto::ticketsrvc::tickets_server::Tickets;
 use crate::proto::ticketsrvc::{
-    CreateTicketRequest, DeleteTicketRequest, FlightStatistics, GetFlightStatisticsRequest,
-    GetTicketRequest, GetTicketWithQrCodeResponse, ListTicketsRequest, Ticket, TicketList,
-    TicketStatus, UpdateTicketRequest,
+    BookItineraryRequest, Booking, CancelBookingRequest, CheckInRequest, CreateBookingRequest,
+    CreateTicketRequest, DeleteTicketRequest, DeleteTicketResponse, FindBookingRequest,
+    FlightStatistics, GetBookingRequest, GetFlightStatisticsRequest, GetQuoteRequest,
+    GetSeatMapRequest, GetTicketRequest, GetTicketWithQrCodeResponse, JoinWaitlistRequest,
+    LeaveWaitlistRequest, ListTicketsRequest, ListWaitlistRequest, Quote, Seat, SeatMap,
+    SetOverbookingFactorRequest, Ticket, TicketList, TicketStatus, UpdateTicketRequest,
+    UpdateTicketStatusRequest, Waitlist, WaitlistEntry,
 };
-use crate::rabbitmq::{Rabbit, UpdateKind};
 
-use self::data::TicketDatabase;
+use self::pricing::{Pricing, PricingContext};
+use self::refunds::RefundRules;
+
+pub use self::data::TicketDatabase;
+pub use self::flight_events::FlightEventHandler;
+pub use self::memory::MemoryDatabase;
+pub use self::mongo::MongoDatabase;
+pub use self::outbox::OutboxRelay;
+pub use self::settings::TicketsSettings;
 
 mod data;
+mod flight_events;
 mod map;
+mod memory;
+mod mongo;
+mod outbox;
+mod pricing;
+mod refunds;
+mod seats;
+mod settings;
+mod status;
 
 pub struct TicketsApp {
-    mongo: Database,
+    db: Arc<dyn TicketDatabase>,
     flightmngr: FlightManager,
     validationsvc: ValidationService,
-    rabbitmq: Rabbit,
+    idempotency_ttl: Duration,
+    checkin_window: Duration,
+    min_connection_time: Duration,
+    overbooking_factor: f64,
+    refund_rules: RefundRules,
+    pricing: Pricing,
 }
 
 #[tonic::async_trait]
@@ -33,19 +66,53 @@ impl Tickets for TicketsApp {
         &self,
         request: Request<ListTicketsRequest>,
     ) -> Result<Response<TicketList>, Status> {
-        let ListTicketsRequest {
-            include_nonvalid,
-            flight_id,
-        } = request.into_inner();
+        let request = request.into_inner();
+        let query = data::TicketQuery::try_from(&request)?;
+        // a page token only resumes the listing it was issued for
+        let query_digest = query_digest(&ListTicketsRequest {
+            page_size: 0,
+            page_token: String::new(),
+            ..request.clone()
+        });
+        let (offset, page_size) =
+            parse_page(request.page_size, &request.page_token, &query_digest)?;
+
+        // fetch one more ticket than requested to know whether another page follows
+        let page = data::Page {
+            skip: offset,
+            limit: page_size as u64 + 1,
+        };
+        let mut result = self.db.list_tickets(&query, page).await?;
 
-        let result = self
-            .mongo
-            .list_tickets(include_nonvalid, flight_id.as_deref())
-            .await?;
+        let next_page_token = if result.len() > page_size as usize {
+            result.truncate(page_size as usize);
+            PageToken::new(offset + page_size as u64, query_digest).encode()
+        } else {
+            String::new()
+        };
 
         let tickets: Vec<Ticket> = result.into_iter().map(Into::into).collect();
 
-        Ok(Response::new(TicketList { tickets }))
+        Ok(Response::new(TicketList {
+            tickets,
+            next_page_token,
+        }))
+    }
+
+    type StreamTicketsStream = Pin<Box<dyn Stream<Item = Result<Ticket, Status>> + Send>>;
+
+    async fn stream_tickets(
+        &self,
+        request: Request<ListTicketsRequest>,
+    ) -> Result<Response<Self::StreamTicketsStream>, Status> {
+        // paging fields are ignored, the whole result set is streamed
+        let query = data::TicketQuery::try_from(request.get_ref())?;
+
+        let stream = self.db.stream_tickets(&query).await?;
+
+        Ok(Response::new(Box::pin(
+            stream.map(|t| t.map(Ticket::from).map_err(Status::from)),
+        )))
     }
 
     async fn get_ticket(
@@ -59,9 +126,9 @@ impl Tickets for TicketsApp {
         let ticket = match query {
             Some(Query::Id(id)) => {
                 let id = convert_str_to_object_id(&id, "invalid id")?;
-                self.mongo.get_ticket(id, allow_nonvalid).await?
+                self.db.get_ticket(id, allow_nonvalid).await?
             }
-            Some(Query::Url(url)) => self.mongo.get_ticket_from_url(url, allow_nonvalid).await?,
+            Some(Query::Url(url)) => self.db.get_ticket_from_url(url, allow_nonvalid).await?,
             None => return Err(Status::invalid_argument("query required")),
         };
 
@@ -74,16 +141,25 @@ impl Tickets for TicketsApp {
     ) -> Result<Response<GetTicketWithQrCodeResponse>, Status> {
         let GetTicketRequest { query, .. } = request.into_inner();
 
-        let ticket: Ticket = match query {
+        let ticket = match query {
             Some(Query::Id(id)) => {
                 let id = convert_str_to_object_id(&id, "invalid id")?;
-                self.mongo.get_ticket(id, false).await?
+                self.db.get_ticket(id, false).await?
             }
-            Some(Query::Url(url)) => self.mongo.get_ticket_from_url(url, false).await?,
+            Some(Query::Url(url)) => self.db.get_ticket_from_url(url, false).await?,
             None => return Err(Status::invalid_argument("query required")),
+        };
+        // the boarding pass is issued by the check-in
+        if status::parse_status(&ticket.ticket_status) != TicketStatus::CheckedIn {
+            return Err(Status::failed_precondition("ticket is not checked in"));
+        }
+        if ticket.oversold {
+            return Err(Status::failed_precondition(
+                "the flight has no seat left for this ticket",
+            ));
         }
-        .into();
 
+        let ticket: Ticket = ticket.into();
         let qr_code = self.validationsvc.make_qr_code(ticket.clone()).await?;
 
         Ok(Response::new(GetTicketWithQrCodeResponse {
@@ -96,51 +172,277 @@ impl Tickets for TicketsApp {
         &self,
         request: Request<CreateTicketRequest>,
     ) -> Result<Response<Ticket>, Status> {
-        let mut new_ticket = request.into_inner().ticket.unwrap_or_default();
+        let (metadata, _, request) = request.into_parts();
+        let CreateTicketRequest {
+            ticket,
+            idempotency_key: key,
+        } = request;
+
+        // a retried request gets the ticket created the first time
+        let idempotency_key = idempotency_key(&metadata, key);
+        let fingerprint = fingerprint(&ticket);
+        if let Some(key) = &idempotency_key {
+            if let Some(ticket) = self.replay(key, &fingerprint).await? {
+                return Ok(Response::new(ticket));
+            }
+        }
+
+        let mut new_ticket = ticket.unwrap_or_default();
         new_ticket.ticket_status = Into::into(TicketStatus::Valid);
+        new_ticket.version = 1;
+
+        new_ticket.url = data::new_ticket_url();
+        let mut new_ticket: data::Ticket = new_ticket.try_into()?;
+        let flight_id = new_ticket.flight_id.clone();
+
+        let flight = self.flightmngr.get_flight(flight_id.clone()).await?;
+        check_not_cancelled(&flight)?;
+        let Plane { cabin_capacity, .. } =
+            self.flightmngr.get_plane(flight.plane_id.clone()).await?;
+        self.price(&flight, cabin_capacity)
+            .await?
+            .apply_to(&mut new_ticket);
+
+        if let Some(seat) = &new_ticket.seat {
+            if !seats::is_valid_seat(seat, cabin_capacity) {
+                return Err(Status::invalid_argument("no such seat on the plane"));
+            }
+        }
 
-        let existing_tickets = self
-            .mongo
-            .get_existing_tickets(&new_ticket.flight_id)
-            .await?;
-        let Plane { cabin_capacity, .. } = self
-            .flightmngr
-            .get_plane_details(new_ticket.flight_id.clone())
-            .await?;
+        let sellable_seats = self.sellable_seats(&flight_id, cabin_capacity).await?;
+        let idempotency = idempotency_key.clone().map(|key| {
+            data::IdempotencyRecord::new(
+                key,
+                fingerprint.clone(),
+                new_ticket._id,
+                self.idempotency_ttl,
+            )
+        });
+
+        let res = self
+            .db
+            .create_ticket(new_ticket, sellable_seats, idempotency)
+            .await;
+        let id = match res {
+            Ok(id) => id,
+            Err(e) => {
+                // a concurrent request with the same key got there first
+                if let (ApplicationError::IdempotencyKeyInUse, Some(key)) = (&e, &idempotency_key) {
+                    if let Some(ticket) = self.replay(key, &fingerprint).await? {
+                        return Ok(Response::new(ticket));
+                    }
+                }
+                return Err(e.into());
+            }
+        };
+
+        let ticket: Ticket = self.db.get_ticket(id, false).await?.into();
+        Ok(Response::new(ticket))
+    }
 
-        // TODO: prevent race condition when creating a ticket
-        if cabin_capacity - existing_tickets <= 0 {
-            return Err(Status::failed_precondition("no seat available"));
+    async fn create_booking(
+        &self,
+        request: Request<CreateBookingRequest>,
+    ) -> Result<Response<Booking>, Status> {
+        let CreateBookingRequest { flight_id, tickets } = request.into_inner();
+        if tickets.is_empty() {
+            return Err(Status::invalid_argument("at least one ticket required"));
         }
 
-        new_ticket.url = Alphanumeric.sample_string(&mut rand::thread_rng(), 64);
-        let id = self.mongo.create_ticket(new_ticket.try_into()?).await?;
+        let mut tickets = tickets
+            .into_iter()
+            .map(|mut t| {
+                t.flight_id = flight_id.clone();
+                t.ticket_status = Into::into(TicketStatus::Valid);
+                t.url = data::new_ticket_url();
+                let mut t: data::Ticket = t.try_into()?;
+                t.version = 1;
+                Ok(t)
+            })
+            .collect::<Result<Vec<_>, Status>>()?;
+
+        let flight = self.flightmngr.get_flight(flight_id.clone()).await?;
+        check_not_cancelled(&flight)?;
+        let Plane { cabin_capacity, .. } =
+            self.flightmngr.get_plane(flight.plane_id.clone()).await?;
+        let price = self.price(&flight, cabin_capacity).await?;
+        for t in &mut tickets {
+            price.apply_to(t);
+        }
 
-        let ticket: Ticket = self.mongo.get_ticket(id, false).await?.into();
+        let mut requested_seats = HashSet::new();
+        for seat in tickets.iter().filter_map(|t| t.seat.as_ref()) {
+            if !seats::is_valid_seat(seat, cabin_capacity) {
+                return Err(Status::invalid_argument("no such seat on the plane"));
+            }
+            if !requested_seats.insert(seat) {
+                return Err(Status::invalid_argument("seat requested twice"));
+            }
+        }
 
-        self.rabbitmq
-            .notify_ticket_update(ticket.clone(), UpdateKind::Create)
-            .await?;
+        // the whole group gets seats, or nobody does
+        let sellable_seats = self.sellable_seats(&flight_id, cabin_capacity).await?;
+        let sellable_seats = HashMap::from([(flight_id, sellable_seats)]);
+        match self.store_booking(tickets, &sellable_seats).await {
+            Ok(booking) => Ok(Response::new(booking.into())),
+            Err(ApplicationError::NoSeatAvailable) => Err(Status::failed_precondition(
+                "not enough seats available for the whole group",
+            )),
+            Err(e) => Err(e.into()),
+        }
+    }
 
-        Ok(Response::new(ticket))
+    async fn book_itinerary(
+        &self,
+        request: Request<BookItineraryRequest>,
+    ) -> Result<Response<Booking>, Status> {
+        let BookItineraryRequest {
+            flight_ids,
+            tickets,
+        } = request.into_inner();
+        if flight_ids.is_empty() {
+            return Err(Status::invalid_argument("at least one flight required"));
+        }
+        if tickets.is_empty() {
+            return Err(Status::invalid_argument("at least one ticket required"));
+        }
+        let mut distinct_flights = HashSet::new();
+        if !flight_ids.iter().all(|f| distinct_flights.insert(f)) {
+            return Err(Status::invalid_argument("flight listed twice"));
+        }
+
+        let mut flights = Vec::with_capacity(flight_ids.len());
+        for flight_id in &flight_ids {
+            let flight = self.flightmngr.get_flight(flight_id.clone()).await?;
+            check_not_cancelled(&flight)?;
+            flights.push(flight);
+        }
+        for (arriving, departing) in flights.iter().zip(flights.iter().skip(1)) {
+            self.check_connection(arriving, departing)?;
+        }
+
+        // every passenger flies every leg, seats are picked per leg afterwards
+        let mut tickets = flight_ids
+            .iter()
+            .flat_map(|flight_id| {
+                tickets.iter().cloned().map(move |mut t| {
+                    t.flight_id = flight_id.clone();
+                    t.ticket_status = Into::into(TicketStatus::Valid);
+                    t.url = data::new_ticket_url();
+                    t.seat = None;
+                    let mut t: data::Ticket = t.try_into()?;
+                    t.version = 1;
+                    Ok(t)
+                })
+            })
+            .collect::<Result<Vec<_>, Status>>()?;
+
+        let mut sellable_seats = HashMap::with_capacity(flights.len());
+        for flight in flights {
+            let Plane { cabin_capacity, .. } =
+                self.flightmngr.get_plane(flight.plane_id.clone()).await?;
+            let price = self.price(&flight, cabin_capacity).await?;
+            for t in tickets.iter_mut().filter(|t| t.flight_id == flight.id) {
+                price.apply_to(t);
+            }
+
+            let sellable = self.sellable_seats(&flight.id, cabin_capacity).await?;
+            sellable_seats.insert(flight.id, sellable);
+        }
+
+        // every leg has seats for the whole group, or nothing is booked
+        match self.store_booking(tickets, &sellable_seats).await {
+            Ok(booking) => Ok(Response::new(booking.into())),
+            Err(ApplicationError::NoSeatAvailable) => Err(Status::failed_precondition(
+                "not enough seats available on every flight",
+            )),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn get_booking(
+        &self,
+        request: Request<GetBookingRequest>,
+    ) -> Result<Response<Booking>, Status> {
+        let GetBookingRequest { booking_reference } = request.into_inner();
+
+        let booking = self.load_booking(&booking_reference).await?;
+
+        Ok(Response::new(booking.into()))
+    }
+
+    async fn find_booking(
+        &self,
+        request: Request<FindBookingRequest>,
+    ) -> Result<Response<Booking>, Status> {
+        let FindBookingRequest {
+            booking_reference,
+            passenger_surname,
+        } = request.into_inner();
+
+        let booking = self.load_booking(&booking_reference).await?;
+
+        // the locator alone is too easy to guess
+        let (_, tickets) = &booking;
+        if !tickets
+            .iter()
+            .any(|t| t.passenger.surname.eq_ignore_ascii_case(&passenger_surname))
+        {
+            return Err(Status::not_found("booking not found"));
+        }
+
+        Ok(Response::new(booking.into()))
+    }
+
+    async fn cancel_booking(
+        &self,
+        request: Request<CancelBookingRequest>,
+    ) -> Result<Response<Booking>, Status> {
+        let CancelBookingRequest { booking_reference } = request.into_inner();
+
+        let cancelled = self.delete_booking_tickets(&booking_reference).await?;
+
+        for ticket in &cancelled {
+            if let Err(status) = self.promote_waitlisted(&ticket.flight_id).await {
+                tracing::error!(
+                    %status,
+                    flight_id = %ticket.flight_id,
+                    "failed to promote waitlisted passenger"
+                );
+            }
+        }
+
+        let booking = self.load_booking(&booking_reference).await?;
+
+        Ok(Response::new(booking.into()))
     }
 
     async fn delete_ticket(
         &self,
         request: Request<DeleteTicketRequest>,
-    ) -> Result<Response<()>, Status> {
-        let DeleteTicketRequest { id } = request.into_inner();
+    ) -> Result<Response<DeleteTicketResponse>, Status> {
+        let DeleteTicketRequest { id, version } = request.into_inner();
         let id = convert_str_to_object_id(&id, "invalid id")?;
 
-        let ticket = self.mongo.get_ticket(id, false).await?.into();
+        let ticket = self.db.get_ticket(id, false).await?;
+        let refund = self.refund(&ticket, &mut HashMap::new()).await?;
 
-        self.mongo.delete_ticket(id).await?;
+        // the refund holds only for the ticket it was computed from
+        let version = version.or(Some(ticket.version));
+        let ticket = self.db.delete_ticket(id, version, refund).await?;
 
-        self.rabbitmq
-            .notify_ticket_update(ticket, UpdateKind::Delete)
-            .await?;
+        // hand the freed seat to the first passenger waiting for one
+        if let Err(status) = self.promote_waitlisted(&ticket.flight_id).await {
+            tracing::error!(
+                %status,
+                flight_id = %ticket.flight_id,
+                "failed to promote waitlisted passenger"
+            );
+        }
 
-        Ok(Response::new(()))
+        Ok(Response::new(DeleteTicketResponse {
+            refund: ticket.refund.map(Into::into),
+        }))
     }
 
     async fn update_ticket(
@@ -151,22 +453,199 @@ impl Tickets for TicketsApp {
             id,
             update,
             update_mask,
+            version,
         } = request.into_inner();
         let id = convert_str_to_object_id(&id, "invalid id")?;
         let update_paths = parse_update_paths(update_mask)?;
         let update = update.ok_or(Status::invalid_argument("update required"))?;
 
-        self.mongo
-            .update_ticket(id, update.try_into()?, update_paths)
+        if let (true, Some(seat)) = (update_paths.contains("seat"), &update.seat) {
+            let ticket = self.db.get_ticket(id, false).await?;
+            let Plane { cabin_capacity, .. } =
+                self.flightmngr.get_plane_details(ticket.flight_id).await?;
+            if !seats::is_valid_seat(seat, cabin_capacity) {
+                return Err(Status::invalid_argument("no such seat on the plane"));
+            }
+        }
+
+        self.db
+            .update_ticket(id, update.try_into()?, update_paths, version)
             .await?;
 
-        let ticket: Ticket = self.mongo.get_ticket(id, false).await?.into();
+        let ticket: Ticket = self.db.get_ticket(id, false).await?.into();
+        Ok(Response::new(ticket))
+    }
 
-        self.rabbitmq
-            .notify_ticket_update(ticket.clone(), UpdateKind::Update)
+    async fn update_ticket_status(
+        &self,
+        request: Request<UpdateTicketStatusRequest>,
+    ) -> Result<Response<Ticket>, Status> {
+        let UpdateTicketStatusRequest {
+            id,
+            status: target,
+            version,
+        } = request.into_inner();
+        let id = convert_str_to_object_id(&id, "invalid id")?;
+        let target = TicketStatus::try_from(target)
+            .or(Err(Status::invalid_argument("invalid ticket status")))?;
+        match target {
+            TicketStatus::Deleted => {
+                return Err(Status::invalid_argument(
+                    "use DeleteTicket to delete a ticket",
+                ))
+            }
+            TicketStatus::CheckedIn => {
+                return Err(Status::invalid_argument("use CheckIn to check a ticket in"))
+            }
+            _ => {}
+        }
+
+        let ticket = self.db.get_ticket(id, false).await?;
+        let current = status::parse_status(&ticket.ticket_status);
+        if !status::can_transition(current, target) {
+            return Err(Status::failed_precondition(format!(
+                "cannot move a ticket from {} to {}",
+                current.as_str_name(),
+                target.as_str_name()
+            )));
+        }
+
+        let ticket = self
+            .db
+            .update_ticket_status(id, current, target, version)
             .await?;
 
-        Ok(Response::new(ticket))
+        // hand the freed seat to the first passenger waiting for one
+        if status::releases_seat(current, target) {
+            if let Err(status) = self.promote_waitlisted(&ticket.flight_id).await {
+                tracing::error!(
+                    %status,
+                    flight_id = %ticket.flight_id,
+                    "failed to promote waitlisted passenger"
+                );
+            }
+        }
+
+        Ok(Response::new(ticket.into()))
+    }
+
+    async fn check_in(
+        &self,
+        request: Request<CheckInRequest>,
+    ) -> Result<Response<GetTicketWithQrCodeResponse>, Status> {
+        let CheckInRequest { id, version } = request.into_inner();
+        let id = convert_str_to_object_id(&id, "invalid id")?;
+
+        let ticket = self.db.get_ticket(id, false).await?;
+        let ticket = match status::parse_status(&ticket.ticket_status) {
+            // it may have been flagged after being checked in
+            TicketStatus::Valid | TicketStatus::CheckedIn if ticket.oversold => {
+                return Err(Status::failed_precondition(
+                    "the flight has no seat left for this ticket",
+                ));
+            }
+            // checking in again issues the boarding pass again
+            TicketStatus::CheckedIn => ticket,
+            TicketStatus::Valid => {
+                let departure = self.departure_time(&ticket.flight_id).await?;
+                self.check_in_window(departure)?;
+
+                self.db.check_in_ticket(id, version).await?
+            }
+            s => {
+                return Err(Status::failed_precondition(format!(
+                    "cannot check in a ticket in status {}",
+                    s.as_str_name()
+                )))
+            }
+        };
+
+        let ticket: Ticket = ticket.into();
+        let qr_code = self.validationsvc.make_qr_code(ticket.clone()).await?;
+
+        Ok(Response::new(GetTicketWithQrCodeResponse {
+            qr_code,
+            ticket: Some(ticket),
+        }))
+    }
+
+    async fn get_seat_map(
+        &self,
+        request: Request<GetSeatMapRequest>,
+    ) -> Result<Response<SeatMap>, Status> {
+        let GetSeatMapRequest { flight_id } = request.into_inner();
+
+        let Plane { cabin_capacity, .. } =
+            self.flightmngr.get_plane_details(flight_id.clone()).await?;
+        let taken: HashSet<String> = self
+            .db
+            .get_taken_seats(&flight_id)
+            .await?
+            .into_iter()
+            .collect();
+
+        let seats = seats::seat_numbers(cabin_capacity)
+            .map(|number| Seat {
+                taken: taken.contains(&number),
+                number,
+            })
+            .collect();
+
+        Ok(Response::new(SeatMap { flight_id, seats }))
+    }
+
+    async fn join_waitlist(
+        &self,
+        request: Request<JoinWaitlistRequest>,
+    ) -> Result<Response<WaitlistEntry>, Status> {
+        let JoinWaitlistRequest {
+            flight_id,
+            passenger,
+            estimated_cargo_weight,
+        } = request.into_inner();
+        let passenger = passenger
+            .ok_or(Status::invalid_argument("missing passenger details"))?
+            .try_into()?;
+
+        let flight = self.flightmngr.get_flight(flight_id.clone()).await?;
+        check_not_cancelled(&flight)?;
+        let Plane { cabin_capacity, .. } = self.flightmngr.get_plane(flight.plane_id).await?;
+        let sellable_seats = self.sellable_seats(&flight_id, cabin_capacity).await?;
+        if self.db.get_existing_tickets(&flight_id).await? < sellable_seats {
+            return Err(Status::failed_precondition(
+                "seats are still available, book a ticket instead",
+            ));
+        }
+
+        let entry = data::WaitlistEntry::new(flight_id, passenger, estimated_cargo_weight);
+        self.db.join_waitlist(entry.clone()).await?;
+
+        Ok(Response::new(entry.into()))
+    }
+
+    async fn leave_waitlist(
+        &self,
+        request: Request<LeaveWaitlistRequest>,
+    ) -> Result<Response<()>, Status> {
+        let LeaveWaitlistRequest { id } = request.into_inner();
+        let id = convert_str_to_object_id(&id, "invalid id")?;
+
+        self.db.leave_waitlist(id).await?;
+
+        Ok(Response::new(()))
+    }
+
+    async fn list_waitlist(
+        &self,
+        request: Request<ListWaitlistRequest>,
+    ) -> Result<Response<Waitlist>, Status> {
+        let ListWaitlistRequest { flight_id } = request.into_inner();
+
+        let entries = self.db.list_waitlist(&flight_id).await?;
+
+        Ok(Response::new(Waitlist {
+            entries: entries.into_iter().map(Into::into).collect(),
+        }))
     }
 
     async fn get_flight_statistics(
@@ -174,30 +653,363 @@ impl Tickets for TicketsApp {
         request: Request<GetFlightStatisticsRequest>,
     ) -> Result<Response<FlightStatistics>, Status> {
         let GetFlightStatisticsRequest { flight_id } = request.into_inner();
-        // let id = convert_str_to_object_id(&flight_id, "invalid id")?;
 
-        let existing_tickets = self.mongo.get_existing_tickets(&flight_id).await?;
-        let airplane = self.flightmngr.get_plane_details(flight_id).await?;
+        let existing_tickets = self.db.get_existing_tickets(&flight_id).await?;
+        let airplane = self.flightmngr.get_plane_details(flight_id.clone()).await?;
+        let sellable_seats = self
+            .sellable_seats(&flight_id, airplane.cabin_capacity)
+            .await?;
 
         Ok(Response::new(FlightStatistics {
             total_seats: airplane.cabin_capacity,
+            sellable_seats,
             reserved_seats: existing_tickets,
         }))
     }
+
+    async fn get_quote(
+        &self,
+        request: Request<GetQuoteRequest>,
+    ) -> Result<Response<Quote>, Status> {
+        let GetQuoteRequest { flight_id } = request.into_inner();
+
+        let flight = self.flightmngr.get_flight(flight_id.clone()).await?;
+        let Plane { cabin_capacity, .. } =
+            self.flightmngr.get_plane(flight.plane_id.clone()).await?;
+        let data::Price {
+            amount_cents,
+            currency,
+        } = self.price(&flight, cabin_capacity).await?;
+
+        Ok(Response::new(Quote {
+            flight_id,
+            fare_cents: amount_cents,
+            currency,
+        }))
+    }
+
+    async fn set_overbooking_factor(
+        &self,
+        request: Request<SetOverbookingFactorRequest>,
+    ) -> Result<Response<()>, Status> {
+        let SetOverbookingFactorRequest {
+            flight_id,
+            overbooking_factor,
+        } = request.into_inner();
+
+        match overbooking_factor {
+            Some(factor) if factor >= 1.0 && factor.is_finite() => {
+                self.db
+                    .set_flight_policy(data::FlightPolicy {
+                        _id: flight_id,
+                        overbooking_factor: factor,
+                    })
+                    .await?
+            }
+            Some(_) => {
+                return Err(Status::invalid_argument(
+                    "overbooking factor must be at least 1",
+                ))
+            }
+            // back to the default
+            None => self.db.delete_flight_policy(&flight_id).await?,
+        }
+
+        Ok(Response::new(()))
+    }
 }
 
 impl TicketsApp {
     pub fn new(
-        mongo_client: Database,
+        db: Arc<dyn TicketDatabase>,
         flightmngr: FlightManager,
         validationsvc: ValidationService,
-        rabbitmq: Rabbit,
+        settings: TicketsSettings,
     ) -> Self {
+        let TicketsSettings {
+            idempotency_ttl,
+            checkin_window,
+            min_connection_time,
+            overbooking_factor,
+            refund_rules,
+            pricing,
+        } = settings;
+
         Self {
-            mongo: mongo_client,
+            db,
             flightmngr,
             validationsvc,
-            rabbitmq,
+            idempotency_ttl,
+            checkin_window,
+            min_connection_time,
+            overbooking_factor,
+            refund_rules,
+            pricing,
+        }
+    }
+
+    /// Scheduled departure of the flight, from flightmngr.
+    async fn departure_time(&self, flight_id: &str) -> Result<DateTime, Status> {
+        let flight = self.flightmngr.get_flight(flight_id.to_string()).await?;
+
+        departure_of(&flight)
+    }
+
+    /// Refund due for cancelling the ticket now. The departure of its flight is only asked to
+    /// flightmngr when the refund depends on it, and is kept in `departures`.
+    async fn refund(
+        &self,
+        ticket: &data::Ticket,
+        departures: &mut HashMap<String, DateTime>,
+    ) -> Result<data::Refund, Status> {
+        if let Some(refund) = self.refund_rules.status_refund(ticket) {
+            return Ok(refund);
         }
+
+        let departure = match departures.get(&ticket.flight_id) {
+            Some(departure) => *departure,
+            None => {
+                let departure = self.departure_time(&ticket.flight_id).await?;
+                departures.insert(ticket.flight_id.clone(), departure);
+                departure
+            }
+        };
+        Ok(self
+            .refund_rules
+            .notice_refund(ticket, departure, DateTime::now()))
     }
+
+    /// Current fare of a ticket on the flight.
+    async fn price(&self, flight: &Flight, cabin_capacity: u32) -> Result<data::Price, Status> {
+        let context = PricingContext {
+            sold_tickets: self.db.get_existing_tickets(&flight.id).await?,
+            cabin_capacity,
+            departure: departure_of(flight)?,
+            now: DateTime::now(),
+        };
+
+        Ok(self.pricing.price(&context))
+    }
+
+    /// Fails unless check-in is open for a flight departing at the given time.
+    fn check_in_window(&self, departure: DateTime) -> Result<(), Status> {
+        let now = DateTime::now();
+        let opens_at = DateTime::from_millis(
+            departure.timestamp_millis() - self.checkin_window.as_millis() as i64,
+        );
+
+        if now < opens_at {
+            return Err(Status::failed_precondition(format!(
+                "check-in opens at {}",
+                opens_at.try_to_rfc3339_string().unwrap_or_default()
+            )));
+        }
+        if now >= departure {
+            return Err(Status::failed_precondition(
+                "check-in is closed, the flight has departed",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Ensure a passenger can make it from one flight of an itinerary to the next.
+    fn check_connection(&self, arriving: &Flight, departing: &Flight) -> Result<(), Status> {
+        if arriving.destination != departing.origin {
+            return Err(Status::invalid_argument(format!(
+                "flight {} does not depart from where flight {} lands",
+                departing.id, arriving.id
+            )));
+        }
+
+        let arrival = arriving
+            .arrival_time
+            .clone()
+            .ok_or_else(|| Status::internal("flight has no arrival time"))?;
+        let departure = departing
+            .departure_time
+            .clone()
+            .ok_or_else(|| Status::internal("flight has no departure time"))?;
+        let connection_millis = convert_timestamp_to_datetime(Some(departure))?.timestamp_millis()
+            - convert_timestamp_to_datetime(Some(arrival))?.timestamp_millis();
+
+        if connection_millis < self.min_connection_time.as_millis() as i64 {
+            return Err(Status::failed_precondition(format!(
+                "connection to flight {} is shorter than the minimum of {} minutes",
+                departing.id,
+                self.min_connection_time.as_secs() / 60
+            )));
+        }
+        Ok(())
+    }
+
+    /// Store the tickets under a new booking, drawing another locator if one is already taken.
+    async fn store_booking(
+        &self,
+        mut tickets: Vec<data::Ticket>,
+        sellable_seats: &HashMap<String, u32>,
+    ) -> Result<(data::Booking, Vec<data::Ticket>), ApplicationError> {
+        const ATTEMPTS: usize = 3;
+
+        for attempt in 1..=ATTEMPTS {
+            let booking = data::Booking::new(data::new_booking_reference());
+            for t in &mut tickets {
+                t.booking_reference = Some(booking._id.clone());
+            }
+
+            match self
+                .db
+                .create_booking(booking.clone(), tickets.clone(), sellable_seats)
+                .await
+            {
+                Ok(()) => return Ok((booking, tickets)),
+                Err(ApplicationError::BookingReferenceTaken) if attempt < ATTEMPTS => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        unreachable!()
+    }
+
+    /// Cancel the booking with a refund for each of its valid tickets, computing the refunds
+    /// again if a ticket changes in the meantime.
+    async fn delete_booking_tickets(
+        &self,
+        booking_reference: &str,
+    ) -> Result<Vec<data::Ticket>, Status> {
+        const ATTEMPTS: usize = 3;
+
+        let mut departures = HashMap::new();
+        for attempt in 1..=ATTEMPTS {
+            let (_, tickets) = self.load_booking(booking_reference).await?;
+            let mut refunds = HashMap::new();
+            for ticket in tickets.iter().filter(|t| t.deleted_at.is_none()) {
+                let refund = self.refund(ticket, &mut departures).await?;
+                refunds.insert(ticket._id, (ticket.version, refund));
+            }
+
+            match self.db.cancel_booking(booking_reference, refunds).await {
+                Ok(cancelled) => return Ok(cancelled),
+                Err(ApplicationError::ConcurrentWrite) if attempt < ATTEMPTS => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        unreachable!()
+    }
+
+    /// Booking with every ticket it ever held, including deleted ones.
+    async fn load_booking(
+        &self,
+        booking_reference: &str,
+    ) -> Result<(data::Booking, Vec<data::Ticket>), Status> {
+        let booking = self.db.get_booking(booking_reference).await?;
+
+        let query = data::TicketQuery {
+            include_nonvalid: true,
+            booking_reference: Some(booking_reference.to_string()),
+            ..Default::default()
+        };
+        let tickets = self
+            .db
+            .stream_tickets(&query)
+            .await?
+            .collect::<Result<Vec<_>, _>>()
+            .await?;
+
+        Ok((booking, tickets))
+    }
+
+    /// Number of tickets that may be sold for the flight, after overbooking.
+    async fn sellable_seats(&self, flight_id: &str, cabin_capacity: u32) -> Result<u32, Status> {
+        Ok(sellable_seats(
+            self.db.as_ref(),
+            self.overbooking_factor,
+            flight_id,
+            cabin_capacity,
+        )
+        .await?)
+    }
+
+    /// Give a freed seat of the flight to the first passenger on its waitlist, if any.
+    async fn promote_waitlisted(&self, flight_id: &str) -> Result<(), Status> {
+        if self.db.list_waitlist(flight_id).await?.is_empty() {
+            return Ok(());
+        }
+
+        let flight = self.flightmngr.get_flight(flight_id.to_string()).await?;
+        // its waitlist is being cleared, nobody gets a ticket on it
+        if is_cancelled(&flight) {
+            return Ok(());
+        }
+        let Plane { cabin_capacity, .. } =
+            self.flightmngr.get_plane(flight.plane_id.clone()).await?;
+        let price = self.price(&flight, cabin_capacity).await?;
+        let sellable_seats = self.sellable_seats(flight_id, cabin_capacity).await?;
+
+        // the waitlist may have emptied, or the seat been sold, in the meantime
+        let promoted = self
+            .db
+            .promote_waitlist_entry(flight_id, sellable_seats, &price)
+            .await?;
+        if let Some(ticket) = promoted {
+            tracing::info!(flight_id, ticket_id = %ticket._id, "promoted waitlisted passenger");
+        }
+        Ok(())
+    }
+
+    /// Ticket created by an earlier request with the same idempotency key, if any.
+    async fn replay(&self, key: &str, fingerprint: &str) -> Result<Option<Ticket>, Status> {
+        let Some(record) = self.db.get_idempotency_record(key).await? else {
+            return Ok(None);
+        };
+
+        if record.fingerprint != fingerprint {
+            return Err(Status::already_exists(
+                "idempotency key already used for a different request",
+            ));
+        }
+
+        // the ticket may have been deleted since
+        let ticket = self.db.get_ticket(record.ticket_id, true).await?;
+        Ok(Some(ticket.into()))
+    }
+}
+
+/// Number of tickets that may be sold for the flight, after its own overbooking factor or
+/// else the default one.
+async fn sellable_seats(
+    db: &dyn TicketDatabase,
+    default_overbooking_factor: f64,
+    flight_id: &str,
+    cabin_capacity: u32,
+) -> Result<u32, ApplicationError> {
+    let factor = db
+        .get_flight_policy(flight_id)
+        .await?
+        .map_or(default_overbooking_factor, |p| p.overbooking_factor);
+
+    Ok((cabin_capacity as f64 * factor).floor() as u32)
+}
+
+/// Whether flightmngr cancelled the flight.
+fn is_cancelled(flight: &Flight) -> bool {
+    FlightStatus::try_from(flight.status) == Ok(FlightStatus::Cancelled)
+}
+
+/// Fails if the flight was cancelled, as no more tickets may be issued for it.
+fn check_not_cancelled(flight: &Flight) -> Result<(), Status> {
+    if is_cancelled(flight) {
+        return Err(Status::failed_precondition(format!(
+            "flight {} is cancelled",
+            flight.id
+        )));
+    }
+    Ok(())
+}
+
+fn departure_of(flight: &Flight) -> Result<DateTime, Status> {
+    let departure = flight
+        .departure_time
+        .clone()
+        .ok_or_else(|| Status::internal("flight has no departure time"))?;
+
+    convert_timestamp_to_datetime(Some(departure))
 }
@@ -0,0 +1,1381 @@
+use backon::{ExponentialBuilder, Retryable};
+use mongodb::bson::{doc, oid::ObjectId, Bson, DateTime, Document};
+use mongodb::error::{BulkWriteFailure, ErrorKind, WriteFailure};
+use mongodb::options::{
+    FindOneAndDeleteOptions, FindOneAndUpdateOptions, FindOptions, IndexOptions, ReplaceOptions,
+    ReturnDocument,
+};
+use mongodb::{Client, ClientSession, Collection, Database, IndexModel};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::future::Future;
+use std::time::Duration;
+use tokio_stream::StreamExt;
+use tonic::async_trait;
+
+use crate::errors::ApplicationError;
+use crate::proto::ticketsrvc::TicketStatus;
+use crate::rabbitmq::UpdateKind;
+
+use super::data::{
+    Booking, DbResult, FlightPolicy, IdempotencyRecord, OutboxEvent, Page, Price, ProcessedMessage,
+    Refund, Ticket, TicketDatabase, TicketOrder, TicketQuery, TicketStream, WaitlistEntry,
+};
+use super::status;
+
+/// Number of seats currently held on a flight, kept in sync with the ticket collection so
+/// that capacity checks can be performed atomically.
+#[derive(Serialize, Deserialize)]
+pub struct FlightSeats {
+    pub _id: String,
+    pub reserved: u32,
+}
+
+const TICKETS: &str = "tickets";
+/// Unique index keeping a seat from being assigned twice on a flight.
+const SEAT_INDEX: &str = "flight_id_1_seat_1";
+/// How long an outbox event is kept once published.
+const SENT_EVENT_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+/// Collection deleted tickets were moved to before they were kept in `TICKETS`.
+const LEGACY_DELETED_TICKETS: &str = "tickets-deleted";
+
+/// Ticket storage backed by MongoDB, which must run as a replica set for transactions.
+pub struct MongoDatabase {
+    client: Client,
+    db: Database,
+}
+
+impl MongoDatabase {
+    pub fn new(client: Client, db_name: &str) -> Self {
+        Self {
+            db: client.database(db_name),
+            client,
+        }
+    }
+
+    fn ticket_collection(&self) -> Collection<Ticket> {
+        self.db.collection(TICKETS)
+    }
+
+    fn flight_seats_collection(&self) -> Collection<FlightSeats> {
+        self.db.collection("flight-seats")
+    }
+
+    fn outbox_collection(&self) -> Collection<OutboxEvent> {
+        self.db.collection("outbox")
+    }
+
+    fn idempotency_collection(&self) -> Collection<IdempotencyRecord> {
+        self.db.collection("idempotency-keys")
+    }
+
+    fn processed_message_collection(&self) -> Collection<ProcessedMessage> {
+        self.db.collection("processed-messages")
+    }
+
+    fn booking_collection(&self) -> Collection<Booking> {
+        self.db.collection("bookings")
+    }
+
+    fn flight_policy_collection(&self) -> Collection<FlightPolicy> {
+        self.db.collection("flight-policies")
+    }
+
+    fn waitlist_collection(&self) -> Collection<WaitlistEntry> {
+        self.db.collection("waitlist")
+    }
+
+    /// Error for a conditional write that matched no ticket: either the ticket does not exist
+    /// or it changed since it was read.
+    async fn missing_ticket_error(
+        &self,
+        id: ObjectId,
+        expected_version: Option<i64>,
+    ) -> ApplicationError {
+        let exists = self
+            .ticket_collection()
+            .count_documents(doc! { "_id": &id, "deleted_at": null }, None)
+            .await;
+        match (exists, expected_version) {
+            (Ok(0), _) => ApplicationError::not_found("ticket not found"),
+            (Ok(_), Some(expected)) => ApplicationError::version_conflict(expected),
+            (Ok(_), None) => ApplicationError::ConcurrentWrite,
+            (Err(e), _) => e.into(),
+        }
+    }
+
+    /// Move a ticket from status `from` to `to`, setting and unsetting the other given fields,
+    /// and give its seat back if the new status does not hold one.
+    async fn set_ticket_status(
+        &self,
+        id: ObjectId,
+        from: TicketStatus,
+        to: TicketStatus,
+        mut set: Document,
+        mut unset: Document,
+        expected_version: Option<i64>,
+    ) -> DbResult<Ticket> {
+        let mut session = self.client.start_session(None).await?;
+        session.start_transaction(None).await?;
+
+        let mut filter = ticket_filter(&id, expected_version);
+        filter.insert("ticket_status", from.as_str_name());
+        set.insert("ticket_status", to.as_str_name());
+        let releases_seat = status::releases_seat(from, to);
+        if releases_seat {
+            unset.insert("seat", "");
+        }
+        let mut update = doc! { "$set": set, "$inc": { "version": 1_i64 } };
+        if !unset.is_empty() {
+            update.insert("$unset", unset);
+        }
+
+        let ticket = self
+            .ticket_collection()
+            .find_one_and_update_with_session(
+                filter,
+                update,
+                FindOneAndUpdateOptions::builder()
+                    .return_document(ReturnDocument::After)
+                    .build(),
+                &mut session,
+            )
+            .await
+            .map_err(ticket_write_error)?;
+        let Some(ticket) = ticket else {
+            return Err(self.missing_ticket_error(id, expected_version).await);
+        };
+        if releases_seat {
+            self.release_seats(&ticket.flight_id, 1, &mut session)
+                .await?;
+        }
+        self.outbox_collection()
+            .insert_one_with_session(
+                OutboxEvent::new(ticket.clone(), UpdateKind::StatusChange),
+                None,
+                &mut session,
+            )
+            .await?;
+
+        session.commit_transaction().await?;
+
+        Ok(ticket)
+    }
+
+    /// Create the indexes the service relies on, if they do not exist yet.
+    pub async fn create_indexes(&self) -> DbResult<()> {
+        // a seat can only be assigned once per flight, deleted tickets give their seat up
+        self.ticket_collection()
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! { "flight_id": 1, "seat": 1 })
+                    .options(
+                        IndexOptions::builder()
+                            .name(SEAT_INDEX.to_string())
+                            .unique(true)
+                            .partial_filter_expression(doc! { "seat": { "$type": "string" } })
+                            .build(),
+                    )
+                    .build(),
+                None,
+            )
+            .await?;
+
+        self.ticket_collection()
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! { "booking_reference": 1 })
+                    .options(IndexOptions::builder().sparse(true).build())
+                    .build(),
+                None,
+            )
+            .await?;
+
+        self.waitlist_collection()
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! { "flight_id": 1, "joined_at": 1, "_id": 1 })
+                    .build(),
+                None,
+            )
+            .await?;
+
+        // let mongodb remove the idempotency keys once they expire
+        self.idempotency_collection()
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! { "expires_at": 1 })
+                    .options(
+                        IndexOptions::builder()
+                            .expire_after(Duration::from_secs(0))
+                            .build(),
+                    )
+                    .build(),
+                None,
+            )
+            .await?;
+
+        // the relay only looks for the events not published yet
+        self.outbox_collection()
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! { "sent_at": 1, "_id": 1 })
+                    .options(
+                        IndexOptions::builder()
+                            .partial_filter_expression(unsent_filter())
+                            .build(),
+                    )
+                    .build(),
+                None,
+            )
+            .await?;
+
+        // and forgets them a while after they are
+        self.outbox_collection()
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! { "sent_at": 1 })
+                    .options(IndexOptions::builder().expire_after(SENT_EVENT_TTL).build())
+                    .build(),
+                None,
+            )
+            .await?;
+
+        // same for the broker messages, long after any redelivery
+        self.processed_message_collection()
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! { "expires_at": 1 })
+                    .options(
+                        IndexOptions::builder()
+                            .expire_after(Duration::from_secs(0))
+                            .build(),
+                    )
+                    .build(),
+                None,
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    /// Remember the message as processed within the transaction, false if it already was.
+    async fn record_processed(
+        &self,
+        message: &ProcessedMessage,
+        session: &mut ClientSession,
+    ) -> DbResult<bool> {
+        let res = self
+            .processed_message_collection()
+            .insert_one_with_session(message, None, session)
+            .await;
+        match res {
+            Ok(_) => Ok(true),
+            Err(e) if is_duplicate_key(&e) => Ok(false),
+            Err(e) => Err(ticket_write_error(e)),
+        }
+    }
+
+    /// Tickets of the flight whose passenger still expects to fly, earliest reservation first.
+    async fn active_tickets(
+        &self,
+        flight_id: &str,
+        session: &mut ClientSession,
+    ) -> DbResult<Vec<Ticket>> {
+        let statuses: Vec<_> = status::ACTIVE.iter().map(|s| s.as_str_name()).collect();
+        let tickets = self
+            .ticket_collection()
+            .find_with_session(
+                doc! {
+                    "flight_id": flight_id,
+                    "deleted_at": null,
+                    "ticket_status": { "$in": statuses },
+                },
+                FindOptions::builder()
+                    .sort(doc! { "reservation_datetime": 1, "_id": 1 })
+                    .build(),
+                &mut *session,
+            )
+            .await?
+            .stream(session)
+            .collect::<Result<Vec<_>, _>>()
+            .await?;
+
+        Ok(tickets)
+    }
+
+    async fn try_create_ticket(
+        &self,
+        ticket: &Ticket,
+        sellable_seats: u32,
+        idempotency: Option<&IdempotencyRecord>,
+    ) -> DbResult<ObjectId> {
+        let mut session = self.client.start_session(None).await?;
+        session.start_transaction(None).await?;
+
+        if let Some(record) = idempotency {
+            // take over an expired record the TTL monitor has not removed yet, while an
+            // unexpired one makes the upsert collide on the key
+            let res = self
+                .idempotency_collection()
+                .replace_one_with_session(
+                    doc! { "_id": &record._id, "expires_at": { "$lte": DateTime::now() } },
+                    record,
+                    ReplaceOptions::builder().upsert(true).build(),
+                    &mut session,
+                )
+                .await;
+            match res {
+                Err(e) if is_duplicate_key(&e) || is_write_conflict(&e) => {
+                    return Err(ApplicationError::IdempotencyKeyInUse)
+                }
+                r => {
+                    r?;
+                }
+            }
+        }
+
+        if !self
+            .reserve_seats(&ticket.flight_id, sellable_seats, 1, &mut session)
+            .await?
+        {
+            return Err(ApplicationError::NoSeatAvailable);
+        }
+
+        let res = self
+            .ticket_collection()
+            .insert_one_with_session(ticket, None, &mut session)
+            .await
+            .map_err(ticket_write_error)?;
+        self.outbox_collection()
+            .insert_one_with_session(
+                OutboxEvent::new(ticket.clone(), UpdateKind::Create),
+                None,
+                &mut session,
+            )
+            .await?;
+
+        session.commit_transaction().await?;
+
+        let id = res.inserted_id.as_object_id().unwrap();
+        Ok(id)
+    }
+
+    async fn try_create_booking(
+        &self,
+        booking: &Booking,
+        tickets: &[Ticket],
+        sellable_seats: &HashMap<String, u32>,
+    ) -> DbResult<()> {
+        let mut session = self.client.start_session(None).await?;
+        session.start_transaction(None).await?;
+
+        let res = self
+            .booking_collection()
+            .insert_one_with_session(booking, None, &mut session)
+            .await;
+        match res {
+            Err(e) if is_duplicate_key(&e) => return Err(ApplicationError::BookingReferenceTaken),
+            r => {
+                r?;
+            }
+        }
+
+        let mut counts: HashMap<&str, u32> = HashMap::new();
+        for ticket in tickets {
+            *counts.entry(&ticket.flight_id).or_default() += 1;
+        }
+        for (flight_id, count) in counts {
+            let sellable_seats = sellable_seats.get(flight_id).copied().unwrap_or_default();
+            if !self
+                .reserve_seats(flight_id, sellable_seats, count, &mut session)
+                .await?
+            {
+                return Err(ApplicationError::NoSeatAvailable);
+            }
+        }
+
+        self.ticket_collection()
+            .insert_many_with_session(tickets, None, &mut session)
+            .await
+            .map_err(ticket_write_error)?;
+        self.outbox_collection()
+            .insert_many_with_session(
+                tickets
+                    .iter()
+                    .map(|t| OutboxEvent::new(t.clone(), UpdateKind::Create)),
+                None,
+                &mut session,
+            )
+            .await?;
+
+        session.commit_transaction().await?;
+
+        Ok(())
+    }
+
+    async fn try_cancel_booking(
+        &self,
+        locator: &str,
+        mut refunds: HashMap<ObjectId, (i64, Refund)>,
+    ) -> DbResult<Vec<Ticket>> {
+        let mut session = self.client.start_session(None).await?;
+        session.start_transaction(None).await?;
+
+        let res = self
+            .booking_collection()
+            .update_one_with_session(
+                doc! { "_id": locator, "cancelled_at": null },
+                doc! { "$set": { "cancelled_at": DateTime::now() } },
+                None,
+                &mut session,
+            )
+            .await
+            .map_err(ticket_write_error)?;
+        if res.matched_count == 0 {
+            // tell a missing booking from a cancelled one
+            self.get_booking(locator).await?;
+            return Err(ApplicationError::BookingCancelled);
+        }
+
+        let mut tickets = self
+            .ticket_collection()
+            .find_with_session(
+                doc! { "booking_reference": locator, "deleted_at": null },
+                None,
+                &mut session,
+            )
+            .await?
+            .stream(&mut session)
+            .collect::<Result<Vec<Ticket>, _>>()
+            .await?;
+        // each refund holds only for the ticket it was computed from
+        if tickets
+            .iter()
+            .any(|t| refunds.get(&t._id).map(|(v, _)| *v) != Some(t.version))
+        {
+            return Err(ApplicationError::ConcurrentWrite);
+        }
+
+        for ticket in &mut tickets {
+            let (_, refund) = refunds.remove(&ticket._id).unwrap();
+            self.ticket_collection()
+                .update_one_with_session(
+                    doc! { "_id": ticket._id },
+                    doc! {
+                        "$set": {
+                            "ticket_status": TicketStatus::Deleted.as_str_name(),
+                            "deleted_at": DateTime::now(),
+                            "refund": refund_document(&refund),
+                        },
+                        "$unset": { "seat": "" },
+                        "$inc": { "version": 1_i64 },
+                    },
+                    None,
+                    &mut session,
+                )
+                .await
+                .map_err(ticket_write_error)?;
+            if status::holds_seat(&ticket.ticket_status) {
+                self.release_seats(&ticket.flight_id, 1, &mut session)
+                    .await?;
+            }
+            ticket.mark_deleted(refund);
+        }
+        if !tickets.is_empty() {
+            self.outbox_collection()
+                .insert_many_with_session(
+                    tickets
+                        .iter()
+                        .map(|t| OutboxEvent::new(t.clone(), UpdateKind::Delete)),
+                    None,
+                    &mut session,
+                )
+                .await?;
+        }
+
+        session.commit_transaction().await?;
+
+        Ok(tickets)
+    }
+
+    async fn try_delete_ticket(
+        &self,
+        id: ObjectId,
+        expected_version: Option<i64>,
+        refund: &Refund,
+    ) -> DbResult<Ticket> {
+        let mut session = self.client.start_session(None).await?;
+        session.start_transaction(None).await?;
+
+        // set as invalid, if it was not changed in the meantime
+        let previous = self
+            .ticket_collection()
+            .find_one_and_update_with_session(
+                ticket_filter(&id, expected_version),
+                doc! {
+                    "$set": {
+                        "ticket_status": TicketStatus::Deleted.as_str_name(),
+                        "deleted_at": DateTime::now(),
+                        "refund": refund_document(refund),
+                    },
+                    "$unset": { "seat": "" },
+                    "$inc": { "version": 1_i64 },
+                },
+                FindOneAndUpdateOptions::builder()
+                    .return_document(ReturnDocument::Before)
+                    .build(),
+                &mut session,
+            )
+            .await
+            .map_err(ticket_write_error)?;
+        let Some(mut ticket) = previous else {
+            return Err(self.missing_ticket_error(id, expected_version).await);
+        };
+        // give the seat back to the flight, unless a no-show or refund already did
+        if status::holds_seat(&ticket.ticket_status) {
+            self.release_seats(&ticket.flight_id, 1, &mut session)
+                .await?;
+        }
+        ticket.mark_deleted(refund.clone());
+        self.outbox_collection()
+            .insert_one_with_session(
+                OutboxEvent::new(ticket.clone(), UpdateKind::Delete),
+                None,
+                &mut session,
+            )
+            .await?;
+
+        session.commit_transaction().await?;
+
+        Ok(ticket)
+    }
+
+    async fn try_update_ticket(
+        &self,
+        id: ObjectId,
+        update: Document,
+        expected_version: Option<i64>,
+    ) -> DbResult<()> {
+        let mut session = self.client.start_session(None).await?;
+        session.start_transaction(None).await?;
+
+        let ticket = self
+            .ticket_collection()
+            .find_one_and_update_with_session(
+                ticket_filter(&id, expected_version),
+                update,
+                FindOneAndUpdateOptions::builder()
+                    .return_document(ReturnDocument::After)
+                    .build(),
+                &mut session,
+            )
+            .await
+            .map_err(ticket_write_error)?;
+        let Some(ticket) = ticket else {
+            return Err(self.missing_ticket_error(id, expected_version).await);
+        };
+        self.outbox_collection()
+            .insert_one_with_session(
+                OutboxEvent::new(ticket, UpdateKind::Update),
+                None,
+                &mut session,
+            )
+            .await?;
+
+        session.commit_transaction().await?;
+
+        Ok(())
+    }
+
+    async fn try_promote_waitlist_entry(
+        &self,
+        flight_id: &str,
+        sellable_seats: u32,
+        price: &Price,
+    ) -> DbResult<Option<Ticket>> {
+        let mut session = self.client.start_session(None).await?;
+        session.start_transaction(None).await?;
+
+        let entry = self
+            .waitlist_collection()
+            .find_one_and_delete_with_session(
+                doc! { "flight_id": flight_id },
+                FindOneAndDeleteOptions::builder()
+                    .sort(doc! { "joined_at": 1, "_id": 1 })
+                    .build(),
+                &mut session,
+            )
+            .await
+            .map_err(ticket_write_error)?;
+        let Some(entry) = entry else {
+            return Ok(None);
+        };
+        if !self
+            .reserve_seats(flight_id, sellable_seats, 1, &mut session)
+            .await?
+        {
+            // the entry stays on the waitlist
+            return Ok(None);
+        }
+
+        let ticket = entry.into_ticket(price);
+        self.ticket_collection()
+            .insert_one_with_session(&ticket, None, &mut session)
+            .await?;
+        self.outbox_collection()
+            .insert_one_with_session(
+                OutboxEvent::new(ticket.clone(), UpdateKind::Create),
+                None,
+                &mut session,
+            )
+            .await?;
+
+        session.commit_transaction().await?;
+
+        Ok(Some(ticket))
+    }
+
+    /// Take `count` more of the `sellable_seats` of the flight within the transaction, false if
+    /// fewer are left.
+    async fn reserve_seats(
+        &self,
+        flight_id: &str,
+        sellable_seats: u32,
+        count: u32,
+        session: &mut ClientSession,
+    ) -> DbResult<bool> {
+        let Some(max_reserved) = sellable_seats.checked_sub(count) else {
+            return Ok(false);
+        };
+
+        // the counter may not exist yet: seed it from the tickets already sold
+        let counter = self
+            .flight_seats_collection()
+            .find_one_with_session(doc! { "_id": flight_id }, None, session)
+            .await?;
+        if counter.is_none() {
+            let existing_tickets = self
+                .ticket_collection()
+                .count_documents_with_session(seat_holding_filter(flight_id), None, session)
+                .await?;
+            let seed = FlightSeats {
+                _id: flight_id.to_string(),
+                reserved: existing_tickets.try_into().unwrap(),
+            };
+            let res = self
+                .flight_seats_collection()
+                .insert_one_with_session(seed, None, session)
+                .await;
+            match res {
+                // a concurrent request created the counter first
+                Err(e) if is_duplicate_key(&e) || is_write_conflict(&e) => {
+                    return Err(ApplicationError::WriteConflict)
+                }
+                r => {
+                    r?;
+                }
+            }
+        }
+
+        let res = self
+            .flight_seats_collection()
+            .update_one_with_session(
+                doc! { "_id": flight_id, "reserved": { "$lte": max_reserved } },
+                doc! { "$inc": { "reserved": count } },
+                None,
+                session,
+            )
+            .await
+            .map_err(ticket_write_error)?;
+        Ok(res.matched_count > 0)
+    }
+
+    /// Give `count` seats of the flight back within the transaction.
+    async fn release_seats(
+        &self,
+        flight_id: &str,
+        count: u32,
+        session: &mut ClientSession,
+    ) -> DbResult<()> {
+        // never go below zero
+        self.flight_seats_collection()
+            .update_one_with_session(
+                doc! { "_id": flight_id },
+                vec![doc! {
+                    "$set": { "reserved": { "$max": [0, { "$subtract": ["$reserved", count] }] } }
+                }],
+                None,
+                session,
+            )
+            .await
+            .map_err(ticket_write_error)?;
+
+        Ok(())
+    }
+
+    /// Move the tickets left in the legacy deleted collection into the ticket collection.
+    ///
+    /// A ticket may be in both collections if a delete was interrupted, the deleted copy wins.
+    pub async fn migrate_deleted_tickets(&self) -> DbResult<()> {
+        let legacy = self.db.collection::<Ticket>(LEGACY_DELETED_TICKETS);
+
+        let mut cursor = legacy.find(None, None).await?;
+        let mut migrated = 0;
+        while let Some(ticket) = cursor.next().await {
+            let mut ticket = ticket?;
+            ticket.ticket_status = TicketStatus::Deleted.as_str_name().to_string();
+            ticket.deleted_at = ticket.deleted_at.or(Some(DateTime::now()));
+
+            self.ticket_collection()
+                .replace_one(
+                    doc! { "_id": &ticket._id },
+                    &ticket,
+                    ReplaceOptions::builder().upsert(true).build(),
+                )
+                .await?;
+            legacy.delete_one(doc! { "_id": &ticket._id }, None).await?;
+            migrated += 1;
+        }
+
+        if migrated > 0 {
+            tracing::info!(migrated, "migrated legacy deleted tickets");
+        }
+        Ok(())
+    }
+}
+
+/// Filter selecting tickets by the given fields, leaving out the deleted tickets unless
+/// requested.
+fn ticket_query(mut filter: Document, include_nonvalid: bool) -> Document {
+    if !include_nonvalid {
+        filter.insert("deleted_at", Bson::Null);
+    }
+    filter
+}
+
+/// Aggregation stages selecting and ordering the tickets matched by the query.
+fn query_pipeline(query: &TicketQuery) -> Vec<Document> {
+    let mut filter = ticket_query(doc! {}, query.include_nonvalid);
+    if let Some(flight_id) = &query.flight_id {
+        filter.insert("flight_id", doc! { "$eq": flight_id });
+    }
+    if let Some(booking_reference) = &query.booking_reference {
+        filter.insert("booking_reference", booking_reference);
+    }
+    if let Some(surname) = &query.passenger_surname {
+        filter.insert("passenger.surname", surname);
+    }
+    if let Some(email) = &query.passenger_email {
+        filter.insert("passenger.email", email);
+    }
+    if let Some(status) = &query.ticket_status {
+        filter.insert("ticket_status", status);
+    }
+
+    let mut reserved = doc! {};
+    if let Some(after) = query.reserved_after {
+        reserved.insert("$gte", after);
+    }
+    if let Some(before) = query.reserved_before {
+        reserved.insert("$lt", before);
+    }
+    if !reserved.is_empty() {
+        filter.insert("reservation_datetime", reserved);
+    }
+
+    vec![
+        doc! { "$match": filter },
+        doc! { "$sort": query_sort(query.order) },
+    ]
+}
+
+fn query_sort(order: TicketOrder) -> Document {
+    match order {
+        TicketOrder::ReservationAsc => doc! { "reservation_datetime": 1, "_id": 1 },
+        TicketOrder::ReservationDesc => doc! { "reservation_datetime": -1, "_id": -1 },
+    }
+}
+
+/// Messages of the duplicate key errors a write ran into, which name the index.
+fn duplicate_key_messages(error: &mongodb::error::Error) -> Vec<&str> {
+    match error.kind.as_ref() {
+        ErrorKind::Write(WriteFailure::WriteError(e)) if e.code == 11000 => {
+            vec![e.message.as_str()]
+        }
+        ErrorKind::BulkWrite(BulkWriteFailure {
+            write_errors: Some(errors),
+            ..
+        }) => errors
+            .iter()
+            .filter(|e| e.code == 11000)
+            .map(|e| e.message.as_str())
+            .collect(),
+        _ => vec![],
+    }
+}
+
+fn is_duplicate_key(error: &mongodb::error::Error) -> bool {
+    !duplicate_key_messages(error).is_empty()
+}
+
+/// Outbox events not published yet, matching the partial index over them.
+fn unsent_filter() -> Document {
+    doc! { "sent_at": { "$type": "null" } }
+}
+
+/// Refund as stored on a deleted ticket.
+fn refund_document(refund: &Refund) -> Document {
+    doc! {
+        "amount_cents": refund.amount_cents,
+        "percent": refund.percent,
+        "reason": &refund.reason,
+    }
+}
+
+/// Filter selecting the tickets that hold a seat on the flight.
+fn seat_holding_filter(flight_id: &str) -> Document {
+    let seatless: Vec<_> = status::SEATLESS.iter().map(|s| s.as_str_name()).collect();
+    doc! {
+        "flight_id": flight_id,
+        "deleted_at": null,
+        "ticket_status": { "$nin": seatless },
+    }
+}
+
+/// Filter selecting a ticket that was not deleted, at the given version if any.
+fn ticket_filter(id: &ObjectId, expected_version: Option<i64>) -> Document {
+    let mut filter = doc! { "_id": id, "deleted_at": null };
+    match expected_version {
+        // tickets stored before versioning have no version field
+        Some(0) => filter.insert("version", doc! { "$in": [0_i64, Bson::Null] }),
+        Some(version) => filter.insert("version", version),
+        None => None,
+    };
+    filter
+}
+
+/// Another transaction is writing the same document.
+fn is_write_conflict(error: &mongodb::error::Error) -> bool {
+    matches!(error.kind.as_ref(), ErrorKind::Command(e) if e.code == 112)
+}
+
+/// Report a write that lost against another transaction, or that collided on the seat index,
+/// as such rather than as an internal error.
+fn ticket_write_error(error: mongodb::error::Error) -> ApplicationError {
+    if is_write_conflict(&error) {
+        ApplicationError::WriteConflict
+    } else if duplicate_key_messages(&error)
+        .iter()
+        .any(|m| m.contains(SEAT_INDEX))
+    {
+        ApplicationError::SeatTaken
+    } else {
+        error.into()
+    }
+}
+
+/// Run a transaction again while it loses write conflicts, which requests for the same flight
+/// run into on its seat counter.
+async fn retry_conflicts<T, F, Fut>(transaction: F) -> DbResult<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = DbResult<T>>,
+{
+    transaction
+        .retry(
+            &ExponentialBuilder::default()
+                .with_min_delay(Duration::from_millis(10))
+                .with_max_times(10)
+                .with_jitter(),
+        )
+        .when(|e| matches!(e, ApplicationError::WriteConflict))
+        .await
+}
+
+#[async_trait]
+impl TicketDatabase for MongoDatabase {
+    async fn ping(&self) -> DbResult<()> {
+        self.db.run_command(doc! { "ping": 1 }, None).await?;
+        Ok(())
+    }
+
+    async fn list_tickets(&self, query: &TicketQuery, page: Page) -> DbResult<Vec<Ticket>> {
+        let mut pipeline = query_pipeline(query);
+        pipeline.push(doc! { "$skip": page.skip as i64 });
+        pipeline.push(doc! { "$limit": page.limit as i64 });
+
+        let stream = self.ticket_collection().aggregate(pipeline, None).await?;
+        let tickets = stream
+            .with_type::<Ticket>()
+            .collect::<Result<Vec<_>, _>>()
+            .await?;
+        Ok(tickets)
+    }
+
+    async fn stream_tickets(&self, query: &TicketQuery) -> DbResult<TicketStream> {
+        // the cursor is polled only as fast as the client consumes the stream, and dropping
+        // the stream when the client goes away kills it on the server
+        let stream = self
+            .ticket_collection()
+            .aggregate(query_pipeline(query), None)
+            .await?;
+        Ok(Box::pin(
+            stream
+                .with_type::<Ticket>()
+                .map(|t| t.map_err(ApplicationError::from)),
+        ))
+    }
+
+    async fn get_ticket(&self, id: ObjectId, allow_nonvalid: bool) -> DbResult<Ticket> {
+        let ticket = self
+            .ticket_collection()
+            .find_one(ticket_query(doc! { "_id": &id }, allow_nonvalid), None)
+            .await?;
+
+        ticket.ok_or_else(|| ApplicationError::not_found("ticket not found"))
+    }
+
+    async fn get_ticket_from_url(&self, url: String, allow_nonvalid: bool) -> DbResult<Ticket> {
+        let ticket = self
+            .ticket_collection()
+            .find_one(ticket_query(doc! { "url": &url }, allow_nonvalid), None)
+            .await?;
+
+        ticket.ok_or_else(|| ApplicationError::not_found("ticket not found"))
+    }
+
+    async fn create_ticket(
+        &self,
+        ticket: Ticket,
+        sellable_seats: u32,
+        idempotency: Option<IdempotencyRecord>,
+    ) -> DbResult<ObjectId> {
+        retry_conflicts(|| self.try_create_ticket(&ticket, sellable_seats, idempotency.as_ref()))
+            .await
+    }
+
+    async fn create_booking(
+        &self,
+        booking: Booking,
+        tickets: Vec<Ticket>,
+        sellable_seats: &HashMap<String, u32>,
+    ) -> DbResult<()> {
+        retry_conflicts(|| self.try_create_booking(&booking, &tickets, sellable_seats)).await
+    }
+
+    async fn get_booking(&self, locator: &str) -> DbResult<Booking> {
+        let booking = self
+            .booking_collection()
+            .find_one(doc! { "_id": locator }, None)
+            .await?;
+
+        booking.ok_or_else(|| ApplicationError::not_found("booking not found"))
+    }
+
+    async fn cancel_booking(
+        &self,
+        locator: &str,
+        refunds: HashMap<ObjectId, (i64, Refund)>,
+    ) -> DbResult<Vec<Ticket>> {
+        retry_conflicts(|| self.try_cancel_booking(locator, refunds.clone())).await
+    }
+
+    async fn get_idempotency_record(&self, key: &str) -> DbResult<Option<IdempotencyRecord>> {
+        let record = self
+            .idempotency_collection()
+            .find_one(
+                doc! { "_id": key, "expires_at": { "$gt": DateTime::now() } },
+                None,
+            )
+            .await?;
+
+        Ok(record)
+    }
+
+    async fn delete_ticket(
+        &self,
+        id: ObjectId,
+        expected_version: Option<i64>,
+        refund: Refund,
+    ) -> DbResult<Ticket> {
+        retry_conflicts(|| self.try_delete_ticket(id, expected_version, &refund)).await
+    }
+
+    async fn update_ticket(
+        &self,
+        id: ObjectId,
+        update: Ticket,
+        update_paths: BTreeSet<String>,
+        expected_version: Option<i64>,
+    ) -> DbResult<()> {
+        let mut updated_doc = doc! {};
+        let mut unset_doc = doc! {};
+
+        let Ticket {
+            passenger, seat, ..
+        } = update;
+
+        for field in update_paths {
+            match field.as_str() {
+                "passenger.ssn" => updated_doc.insert(field, passenger.ssn.clone()),
+                "passenger.name" => updated_doc.insert(field, passenger.name.clone()),
+                "passenger.surname" => updated_doc.insert(field, passenger.surname.clone()),
+                "passenger.birth_date" => updated_doc.insert(field, passenger.birth_date),
+                "passenger.email" => updated_doc.insert(field, passenger.email.clone()),
+                "seat" => match &seat {
+                    Some(seat) => updated_doc.insert(field, seat.clone()),
+                    None => unset_doc.insert(field, ""),
+                },
+                f => return Err(ApplicationError::invalid_update_path(f.to_string())),
+            };
+        }
+
+        let mut update = doc! { "$set": updated_doc, "$inc": { "version": 1_i64 } };
+        if !unset_doc.is_empty() {
+            update.insert("$unset", unset_doc);
+        }
+
+        retry_conflicts(|| self.try_update_ticket(id, update.clone(), expected_version)).await
+    }
+
+    async fn get_taken_seats(&self, flight_id: &str) -> DbResult<Vec<String>> {
+        let seats = self
+            .ticket_collection()
+            .distinct(
+                "seat",
+                doc! { "flight_id": flight_id, "deleted_at": null, "seat": { "$type": "string" } },
+                None,
+            )
+            .await?;
+
+        Ok(seats
+            .into_iter()
+            .filter_map(|s| s.as_str().map(String::from))
+            .collect())
+    }
+
+    async fn update_ticket_status(
+        &self,
+        id: ObjectId,
+        from: TicketStatus,
+        to: TicketStatus,
+        expected_version: Option<i64>,
+    ) -> DbResult<Ticket> {
+        // back to before the check-in
+        let unset = if to == TicketStatus::Valid {
+            doc! { "checked_in_at": "" }
+        } else {
+            doc! {}
+        };
+
+        retry_conflicts(|| {
+            self.set_ticket_status(id, from, to, doc! {}, unset.clone(), expected_version)
+        })
+        .await
+    }
+
+    async fn check_in_ticket(
+        &self,
+        id: ObjectId,
+        expected_version: Option<i64>,
+    ) -> DbResult<Ticket> {
+        let set = doc! { "checked_in_at": DateTime::now() };
+
+        retry_conflicts(|| {
+            self.set_ticket_status(
+                id,
+                TicketStatus::Valid,
+                TicketStatus::CheckedIn,
+                set.clone(),
+                doc! {},
+                expected_version,
+            )
+        })
+        .await
+    }
+
+    async fn get_existing_tickets(&self, flight_id: &str) -> DbResult<u32> {
+        let count = self
+            .ticket_collection()
+            .count_documents(seat_holding_filter(flight_id), None)
+            .await?;
+
+        Ok(count.try_into().unwrap())
+    }
+
+    async fn get_flight_policy(&self, flight_id: &str) -> DbResult<Option<FlightPolicy>> {
+        let policy = self
+            .flight_policy_collection()
+            .find_one(doc! { "_id": flight_id }, None)
+            .await?;
+
+        Ok(policy)
+    }
+
+    async fn set_flight_policy(&self, policy: FlightPolicy) -> DbResult<()> {
+        self.flight_policy_collection()
+            .replace_one(
+                doc! { "_id": &policy._id },
+                &policy,
+                ReplaceOptions::builder().upsert(true).build(),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn delete_flight_policy(&self, flight_id: &str) -> DbResult<()> {
+        self.flight_policy_collection()
+            .delete_one(doc! { "_id": flight_id }, None)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn join_waitlist(&self, entry: WaitlistEntry) -> DbResult<()> {
+        self.waitlist_collection().insert_one(entry, None).await?;
+        Ok(())
+    }
+
+    async fn leave_waitlist(&self, id: ObjectId) -> DbResult<()> {
+        let res = self
+            .waitlist_collection()
+            .delete_one(doc! { "_id": &id }, None)
+            .await?;
+        if res.deleted_count == 0 {
+            return Err(ApplicationError::not_found("waitlist entry not found"));
+        }
+
+        Ok(())
+    }
+
+    async fn list_waitlist(&self, flight_id: &str) -> DbResult<Vec<WaitlistEntry>> {
+        let options = FindOptions::builder()
+            .sort(doc! { "joined_at": 1, "_id": 1 })
+            .build();
+
+        let stream = self
+            .waitlist_collection()
+            .find(doc! { "flight_id": flight_id }, options)
+            .await?;
+        let entries = stream.collect::<Result<Vec<_>, _>>().await?;
+        Ok(entries)
+    }
+
+    async fn promote_waitlist_entry(
+        &self,
+        flight_id: &str,
+        sellable_seats: u32,
+        price: &Price,
+    ) -> DbResult<Option<Ticket>> {
+        retry_conflicts(|| self.try_promote_waitlist_entry(flight_id, sellable_seats, price)).await
+    }
+
+    async fn cancel_flight_tickets(
+        &self,
+        flight_id: &str,
+        message: ProcessedMessage,
+    ) -> DbResult<Option<Vec<Ticket>>> {
+        let mut session = self.client.start_session(None).await?;
+        session.start_transaction(None).await?;
+
+        if !self.record_processed(&message, &mut session).await? {
+            return Ok(None);
+        }
+
+        self.waitlist_collection()
+            .delete_many_with_session(doc! { "flight_id": flight_id }, None, &mut session)
+            .await
+            .map_err(ticket_write_error)?;
+
+        let mut tickets = self.active_tickets(flight_id, &mut session).await?;
+        if tickets.is_empty() {
+            session.commit_transaction().await?;
+            return Ok(Some(tickets));
+        }
+
+        let ids: Vec<_> = tickets.iter().map(|t| t._id).collect();
+        self.ticket_collection()
+            .update_many_with_session(
+                doc! { "_id": { "$in": ids } },
+                doc! {
+                    "$set": { "ticket_status": TicketStatus::FlightCancelled.as_str_name() },
+                    "$inc": { "version": 1_i64 },
+                },
+                None,
+                &mut session,
+            )
+            .await
+            .map_err(ticket_write_error)?;
+
+        for ticket in &mut tickets {
+            ticket.ticket_status = TicketStatus::FlightCancelled.as_str_name().to_string();
+            ticket.version += 1;
+        }
+        self.outbox_collection()
+            .insert_many_with_session(
+                tickets
+                    .iter()
+                    .map(|t| OutboxEvent::new(t.clone(), UpdateKind::StatusChange)),
+                None,
+                &mut session,
+            )
+            .await?;
+
+        session.commit_transaction().await?;
+
+        Ok(Some(tickets))
+    }
+
+    async fn flag_oversold_tickets(
+        &self,
+        flight_id: &str,
+        sellable_seats: u32,
+        message: ProcessedMessage,
+    ) -> DbResult<Option<Vec<Ticket>>> {
+        let mut session = self.client.start_session(None).await?;
+        session.start_transaction(None).await?;
+
+        if !self.record_processed(&message, &mut session).await? {
+            return Ok(None);
+        }
+
+        let mut changed = vec![];
+        let tickets = self.active_tickets(flight_id, &mut session).await?;
+        for (i, mut ticket) in tickets.into_iter().enumerate() {
+            let oversold = i >= sellable_seats as usize;
+            if ticket.oversold != oversold {
+                ticket.oversold = oversold;
+                ticket.version += 1;
+                changed.push(ticket);
+            }
+        }
+        if changed.is_empty() {
+            session.commit_transaction().await?;
+            return Ok(Some(changed));
+        }
+
+        for oversold in [true, false] {
+            let ids: Vec<_> = changed
+                .iter()
+                .filter(|t| t.oversold == oversold)
+                .map(|t| t._id)
+                .collect();
+            if ids.is_empty() {
+                continue;
+            }
+
+            self.ticket_collection()
+                .update_many_with_session(
+                    doc! { "_id": { "$in": ids } },
+                    doc! {
+                        "$set": { "oversold": oversold },
+                        "$inc": { "version": 1_i64 },
+                    },
+                    None,
+                    &mut session,
+                )
+                .await
+                .map_err(ticket_write_error)?;
+        }
+        self.outbox_collection()
+            .insert_many_with_session(
+                changed
+                    .iter()
+                    .map(|t| OutboxEvent::new(t.clone(), UpdateKind::Update)),
+                None,
+                &mut session,
+            )
+            .await?;
+
+        session.commit_transaction().await?;
+
+        Ok(Some(changed))
+    }
+
+    async fn pending_events(&self, limit: u64) -> DbResult<Vec<OutboxEvent>> {
+        let options = FindOptions::builder()
+            .sort(doc! { "_id": 1 })
+            .limit(limit as i64)
+            .build();
+
+        let stream = self
+            .outbox_collection()
+            .find(unsent_filter(), options)
+            .await?;
+        let events = stream.collect::<Result<Vec<_>, _>>().await?;
+        Ok(events)
+    }
+
+    async fn mark_event_sent(&self, id: ObjectId) -> DbResult<()> {
+        self.outbox_collection()
+            .update_one(
+                doc! { "_id": &id },
+                doc! { "$set": { "sent_at": DateTime::now() } },
+                None,
+            )
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::tickets::data::tests::{create_in_parallel, refund_and_delete};
+
+    /// Database of its own on the replica set in `TEST_DATABASE_URL`, to be dropped once done.
+    async fn test_database() -> Arc<MongoDatabase> {
+        let url = std::env::var("TEST_DATABASE_URL").expect("TEST_DATABASE_URL is not set");
+        let client = Client::with_uri_str(url).await.unwrap();
+        let db_name = format!("ticket-svc-test-{}", ObjectId::new());
+        let db = Arc::new(MongoDatabase::new(client, &db_name));
+        db.create_indexes().await.unwrap();
+        db
+    }
+
+    async fn reserved_seats(db: &MongoDatabase, flight_id: &str) -> Option<u32> {
+        db.flight_seats_collection()
+            .find_one(doc! { "_id": flight_id }, None)
+            .await
+            .unwrap()
+            .map(|s| s.reserved)
+    }
+
+    // Run with `TEST_DATABASE_URL=mongodb://... cargo test -- --ignored`.
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore = "needs a MongoDB replica set in TEST_DATABASE_URL"]
+    async fn parallel_creates_fill_exactly_the_sellable_seats() {
+        let db = test_database().await;
+
+        let created = create_in_parallel(db.clone(), "AZ610", 7, 20).await;
+        let existing_tickets = db.get_existing_tickets("AZ610").await.unwrap();
+        let reserved = reserved_seats(&db, "AZ610").await;
+        db.db.drop(None).await.unwrap();
+
+        assert_eq!(created, 7);
+        assert_eq!(existing_tickets, 7);
+        assert_eq!(reserved, Some(7));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore = "needs a MongoDB replica set in TEST_DATABASE_URL"]
+    async fn deleting_a_refunded_ticket_frees_its_seat_once() {
+        let db = test_database().await;
+
+        refund_and_delete(db.as_ref(), "AZ610", 3).await;
+        let reserved_after_delete = reserved_seats(&db, "AZ610").await;
+        let created = create_in_parallel(db.clone(), "AZ610", 3, 5).await;
+        let reserved = reserved_seats(&db, "AZ610").await;
+        db.db.drop(None).await.unwrap();
+
+        assert_eq!(reserved_after_delete, Some(2));
+        assert_eq!(created, 1);
+        assert_eq!(reserved, Some(3));
+    }
+}
@@ -0,0 +1,82 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use backon::{ExponentialBuilder, Retryable};
+use tracing::Instrument;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+use crate::errors::ApplicationError;
+use crate::proto::ticketsrvc::Ticket;
+use crate::rabbitmq::Rabbit;
+use crate::telemetry;
+
+use super::data::TicketDatabase;
+
+const BATCH_SIZE: u64 = 100;
+
+/// Background task publishing the events recorded in the outbox to the broker.
+///
+/// Events are published in the order they were recorded and marked as sent only once the
+/// broker accepted them, so every event is delivered at least once.
+pub struct OutboxRelay {
+    db: Arc<dyn TicketDatabase>,
+    rabbitmq: Rabbit,
+    poll_interval: Duration,
+}
+
+impl OutboxRelay {
+    pub fn new(db: Arc<dyn TicketDatabase>, rabbitmq: Rabbit, poll_interval: Duration) -> Self {
+        Self {
+            db,
+            rabbitmq,
+            poll_interval,
+        }
+    }
+
+    pub async fn run(self) {
+        loop {
+            match self.relay_pending().await {
+                // more events may be waiting
+                Ok(n) if n as u64 == BATCH_SIZE => continue,
+                Ok(_) => {}
+                Err(error) => tracing::error!(%error, "failed to relay outbox events"),
+            }
+            tokio::time::sleep(self.poll_interval).await;
+        }
+    }
+
+    async fn relay_pending(&self) -> Result<usize, ApplicationError> {
+        let events = self.db.pending_events(BATCH_SIZE).await?;
+
+        for event in &events {
+            let ticket: Ticket = event.ticket.clone().into();
+
+            // continue the trace of the request that recorded the event
+            let span = tracing::info_span!("publish ticket update", event_id = %event._id);
+            span.set_parent(telemetry::extract_context(&event.trace_context));
+
+            let res = (|| async {
+                self.rabbitmq
+                    .notify_ticket_update(&event._id.to_hex(), ticket.clone(), event.update_kind)
+                    .await
+            })
+            .retry(&ExponentialBuilder::default().with_max_times(5))
+            .when(|e| !matches!(e, ApplicationError::PublishReturned { .. }))
+            .instrument(span)
+            .await;
+            match res {
+                Ok(()) => {}
+                // nobody listens for ticket updates: drop the event rather than hold back
+                // the ones after it
+                Err(ApplicationError::PublishReturned { reason }) => {
+                    tracing::warn!(event_id = %event._id, %reason, "dropped unroutable ticket update");
+                }
+                Err(e) => return Err(e),
+            }
+
+            self.db.mark_event_sent(event._id).await?;
+        }
+
+        Ok(events.len())
+    }
+}
@@ -0,0 +1,221 @@
+use std::time::Duration;
+
+use mongodb::bson::DateTime;
+
+use crate::parse::parse_tiers;
+
+use super::data::Price;
+
+/// Load factor tiers used when none are configured: 20% more once half of the seats are
+/// sold, 50% more from 80%.
+pub const DEFAULT_LOAD_FACTOR_TIERS: &str = "50:120,80:150";
+
+/// Departure tiers used when none are configured: 20% more in the last week before
+/// departure, 50% more in the last day.
+pub const DEFAULT_DEPARTURE_TIERS: &str = "168:120,24:150";
+
+/// What the fare of a ticket may depend on.
+pub struct PricingContext {
+    /// Tickets already sold on the flight.
+    pub sold_tickets: u32,
+    pub cabin_capacity: u32,
+    pub departure: DateTime,
+    pub now: DateTime,
+}
+
+/// Decides the fare of a ticket, in cents.
+pub trait PricingStrategy: Send + Sync {
+    fn fare_cents(&self, context: &PricingContext) -> i64;
+}
+
+/// Same fare on every flight.
+pub struct FixedFare {
+    base_fare_cents: i64,
+}
+
+impl FixedFare {
+    pub fn new(base_fare_cents: i64) -> Self {
+        Self { base_fare_cents }
+    }
+}
+
+impl PricingStrategy for FixedFare {
+    fn fare_cents(&self, _: &PricingContext) -> i64 {
+        self.base_fare_cents
+    }
+}
+
+/// Base fare raised as the flight fills up.
+///
+/// Tiers are `<load factor %>:<% of the base fare>`, the highest load factor reached applies.
+pub struct LoadFactorPricing {
+    base_fare_cents: i64,
+    /// Highest load factor first.
+    tiers: Vec<(u32, u32)>,
+}
+
+impl LoadFactorPricing {
+    pub fn new(base_fare_cents: i64, tiers: &str) -> Result<Self, String> {
+        let mut tiers = parse_tiers::<u32>(tiers)?;
+        tiers.sort_by(|a, b| b.0.cmp(&a.0));
+
+        Ok(Self {
+            base_fare_cents,
+            tiers,
+        })
+    }
+}
+
+impl PricingStrategy for LoadFactorPricing {
+    fn fare_cents(&self, context: &PricingContext) -> i64 {
+        let load_percent = if context.cabin_capacity == 0 {
+            100
+        } else {
+            (context.sold_tickets as u64 * 100 / context.cabin_capacity as u64) as u32
+        };
+
+        let percent = self
+            .tiers
+            .iter()
+            .find(|(load, _)| load_percent >= *load)
+            .map_or(100, |(_, percent)| *percent);
+        self.base_fare_cents * i64::from(percent) / 100
+    }
+}
+
+/// Base fare raised as departure gets closer.
+///
+/// Tiers are `<hours before departure>:<% of the base fare>`, the closest tier to departure
+/// that has been reached applies.
+pub struct DeparturePricing {
+    base_fare_cents: i64,
+    /// Shortest notice first.
+    tiers: Vec<(Duration, u32)>,
+}
+
+impl DeparturePricing {
+    pub fn new(base_fare_cents: i64, tiers: &str) -> Result<Self, String> {
+        let mut tiers: Vec<_> = parse_tiers::<u64>(tiers)?
+            .into_iter()
+            .map(|(hours, percent)| (Duration::from_secs(hours * 60 * 60), percent))
+            .collect();
+        tiers.sort_by(|a, b| a.0.cmp(&b.0));
+
+        Ok(Self {
+            base_fare_cents,
+            tiers,
+        })
+    }
+}
+
+impl PricingStrategy for DeparturePricing {
+    fn fare_cents(&self, context: &PricingContext) -> i64 {
+        let notice_millis = context.departure.timestamp_millis() - context.now.timestamp_millis();
+        let notice = Duration::from_millis(notice_millis.max(0) as u64);
+
+        let percent = self
+            .tiers
+            .iter()
+            .find(|(within, _)| notice <= *within)
+            .map_or(100, |(_, percent)| *percent);
+        self.base_fare_cents * i64::from(percent) / 100
+    }
+}
+
+/// Prices tickets in a single currency, with the configured strategy.
+pub struct Pricing {
+    strategy: Box<dyn PricingStrategy>,
+    currency: String,
+}
+
+impl Pricing {
+    pub fn new(strategy: Box<dyn PricingStrategy>, currency: String) -> Self {
+        Self { strategy, currency }
+    }
+
+    pub fn price(&self, context: &PricingContext) -> Price {
+        Price {
+            amount_cents: self.strategy.fare_cents(context),
+            currency: self.currency.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE_FARE_CENTS: i64 = 10_000;
+    const HOUR_MILLIS: i64 = 60 * 60 * 1000;
+
+    fn context(sold_tickets: u32, cabin_capacity: u32, notice_millis: i64) -> PricingContext {
+        let now = DateTime::from_millis(1_700_000_000_000);
+
+        PricingContext {
+            sold_tickets,
+            cabin_capacity,
+            departure: DateTime::from_millis(now.timestamp_millis() + notice_millis),
+            now,
+        }
+    }
+
+    #[test]
+    fn load_factor_tier_applies_once_reached() {
+        let pricing = LoadFactorPricing::new(BASE_FARE_CENTS, DEFAULT_LOAD_FACTOR_TIERS).unwrap();
+        let fare = |sold| pricing.fare_cents(&context(sold, 100, 0));
+
+        assert_eq!(fare(0), 10_000);
+        assert_eq!(fare(49), 10_000);
+        assert_eq!(fare(50), 12_000);
+        assert_eq!(fare(79), 12_000);
+        assert_eq!(fare(80), 15_000);
+        assert_eq!(fare(120), 15_000);
+    }
+
+    #[test]
+    fn plane_without_seats_counts_as_full() {
+        let pricing = LoadFactorPricing::new(BASE_FARE_CENTS, DEFAULT_LOAD_FACTOR_TIERS).unwrap();
+
+        assert_eq!(pricing.fare_cents(&context(0, 0, 0)), 15_000);
+    }
+
+    #[test]
+    fn departure_tier_applies_up_to_its_notice() {
+        let pricing = DeparturePricing::new(BASE_FARE_CENTS, DEFAULT_DEPARTURE_TIERS).unwrap();
+        let fare = |notice_millis| pricing.fare_cents(&context(0, 100, notice_millis));
+
+        assert_eq!(fare(168 * HOUR_MILLIS + 1), 10_000);
+        assert_eq!(fare(168 * HOUR_MILLIS), 12_000);
+        assert_eq!(fare(24 * HOUR_MILLIS + 1), 12_000);
+        assert_eq!(fare(24 * HOUR_MILLIS), 15_000);
+        assert_eq!(fare(-HOUR_MILLIS), 15_000);
+    }
+
+    #[test]
+    fn no_tiers_means_the_base_fare() {
+        let pricing = DeparturePricing::new(BASE_FARE_CENTS, "").unwrap();
+
+        assert_eq!(pricing.fare_cents(&context(0, 100, 0)), BASE_FARE_CENTS);
+    }
+
+    #[test]
+    fn bad_tiers_rejected() {
+        for tiers in ["50", "50:", "half:120", "50:120,80", "-1:120"] {
+            assert!(
+                LoadFactorPricing::new(BASE_FARE_CENTS, tiers).is_err(),
+                "{tiers}"
+            );
+            assert!(
+                DeparturePricing::new(BASE_FARE_CENTS, tiers).is_err(),
+                "{tiers}"
+            );
+        }
+    }
+
+    #[test]
+    fn fixed_fare_ignores_the_context() {
+        let pricing = FixedFare::new(BASE_FARE_CENTS);
+
+        assert_eq!(pricing.fare_cents(&context(99, 100, 0)), BASE_FARE_CENTS);
+    }
+}
@@ -0,0 +1,174 @@
+use std::str::FromStr;
+use std::time::Duration;
+
+use mongodb::bson::DateTime;
+
+use crate::parse::parse_tiers;
+use crate::proto::ticketsrvc::TicketStatus;
+
+use super::data::{Refund, Ticket};
+use super::status;
+
+/// Share of the fare refunded for cancelling at least `min_notice` before departure.
+#[derive(Debug, Clone, Copy)]
+struct RefundTier {
+    min_notice: Duration,
+    percent: u32,
+}
+
+/// Fare rules deciding how much of the fare is refunded when a ticket is cancelled, from how
+/// long before departure it is cancelled.
+///
+/// Parsed from comma separated `<hours>:<percent>` tiers, e.g. `168:100,24:50` refunds the
+/// whole fare up to a week before departure, half of it up to a day before, and nothing
+/// later.
+#[derive(Debug, Clone)]
+pub struct RefundRules {
+    /// Longest notice first.
+    tiers: Vec<RefundTier>,
+}
+
+impl FromStr for RefundRules {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut tiers = parse_tiers::<u64>(s)?
+            .into_iter()
+            .map(|(hours, percent)| {
+                if percent > 100 {
+                    return Err(format!("cannot refund {percent}% of the fare"));
+                }
+                Ok(RefundTier {
+                    min_notice: Duration::from_secs(hours * 60 * 60),
+                    percent,
+                })
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        tiers.sort_by(|a, b| b.min_notice.cmp(&a.min_notice));
+
+        Ok(Self { tiers })
+    }
+}
+
+impl RefundRules {
+    /// Refund due for cancelling the ticket whatever the notice given, if its status decides
+    /// it.
+    pub fn status_refund(&self, ticket: &Ticket) -> Option<Refund> {
+        let (percent, reason) = match status::parse_status(&ticket.ticket_status) {
+            TicketStatus::FlightCancelled => (100, "flight cancelled"),
+            TicketStatus::Refunded => (0, "ticket already refunded"),
+            TicketStatus::Boarded => (0, "ticket already used"),
+            TicketStatus::NoShow => (0, "passenger did not show up"),
+            _ => return None,
+        };
+
+        Some(fare_share(ticket, percent, reason.to_string()))
+    }
+
+    /// Refund due for cancelling the ticket at `now`, for a flight departing at `departure`,
+    /// when its status does not decide it.
+    pub fn notice_refund(&self, ticket: &Ticket, departure: DateTime, now: DateTime) -> Refund {
+        let (percent, reason) = self.notice_percent(departure, now);
+
+        fare_share(ticket, percent, reason)
+    }
+
+    fn notice_percent(&self, departure: DateTime, now: DateTime) -> (u32, String) {
+        let notice_millis = departure.timestamp_millis() - now.timestamp_millis();
+        if notice_millis <= 0 {
+            return (0, String::from("cancelled after departure"));
+        }
+        let notice = Duration::from_millis(notice_millis as u64);
+
+        match self.tiers.iter().find(|t| notice >= t.min_notice) {
+            Some(tier) => (
+                tier.percent,
+                format!(
+                    "cancelled at least {} hours before departure",
+                    tier.min_notice.as_secs() / (60 * 60)
+                ),
+            ),
+            None => (0, String::from("cancelled too close to departure")),
+        }
+    }
+}
+
+fn fare_share(ticket: &Ticket, percent: u32, reason: String) -> Refund {
+    Refund {
+        amount_cents: ticket.fare_cents * i64::from(percent) / 100,
+        percent,
+        reason,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tickets::data::tests::new_ticket;
+
+    const RULES: &str = "168:100,24:50";
+    const HOUR_MILLIS: i64 = 60 * 60 * 1000;
+
+    fn refund_with_notice(rules: &str, notice_millis: i64) -> Refund {
+        let rules: RefundRules = rules.parse().unwrap();
+        let now = DateTime::from_millis(1_700_000_000_000);
+        let departure = DateTime::from_millis(now.timestamp_millis() + notice_millis);
+
+        rules.notice_refund(&new_ticket("AZ610"), departure, now)
+    }
+
+    #[test]
+    fn tier_applies_from_its_notice_on() {
+        assert_eq!(refund_with_notice(RULES, 168 * HOUR_MILLIS).percent, 100);
+        assert_eq!(refund_with_notice(RULES, 168 * HOUR_MILLIS - 1).percent, 50);
+        assert_eq!(refund_with_notice(RULES, 24 * HOUR_MILLIS).percent, 50);
+        assert_eq!(refund_with_notice(RULES, 24 * HOUR_MILLIS - 1).percent, 0);
+    }
+
+    #[test]
+    fn tiers_given_in_any_order() {
+        let rules = "24:50,168:100";
+
+        assert_eq!(refund_with_notice(rules, 200 * HOUR_MILLIS).percent, 100);
+        assert_eq!(refund_with_notice(rules, 48 * HOUR_MILLIS).percent, 50);
+    }
+
+    #[test]
+    fn nothing_refunded_after_departure() {
+        let refund = refund_with_notice("0:100", 0);
+
+        assert_eq!(refund.percent, 0);
+        assert_eq!(refund.amount_cents, 0);
+    }
+
+    #[test]
+    fn amount_is_the_share_of_the_fare() {
+        let ticket = new_ticket("AZ610");
+        let refund = refund_with_notice("24:50", 48 * HOUR_MILLIS);
+
+        assert_eq!(refund.amount_cents, ticket.fare_cents / 2);
+    }
+
+    #[test]
+    fn status_decides_the_refund_of_some_tickets() {
+        let rules: RefundRules = "24:50".parse().unwrap();
+        let mut ticket = new_ticket("AZ610");
+
+        assert!(rules.status_refund(&ticket).is_none());
+
+        ticket.ticket_status = TicketStatus::FlightCancelled.as_str_name().to_string();
+        let refund = rules.status_refund(&ticket).unwrap();
+        assert_eq!(refund.percent, 100);
+        assert_eq!(refund.amount_cents, ticket.fare_cents);
+
+        ticket.ticket_status = TicketStatus::NoShow.as_str_name().to_string();
+        assert_eq!(rules.status_refund(&ticket).unwrap().percent, 0);
+    }
+
+    #[test]
+    fn bad_rules_rejected() {
+        for rules in ["24:150", "24", "a day:50", "24:half", "-1:50"] {
+            assert!(rules.parse::<RefundRules>().is_err(), "{rules}");
+        }
+    }
+}
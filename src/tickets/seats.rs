@@ -0,0 +1,19 @@
+const SEAT_LETTERS: &[char] = &['A', 'B', 'C', 'D', 'E', 'F'];
+
+/// Seats of a cabin with the given capacity, in rows of six numbered from 1 and lettered A to F.
+pub fn seat_numbers(capacity: u32) -> impl Iterator<Item = String> {
+    let per_row = SEAT_LETTERS.len() as u32;
+
+    (0..capacity).map(move |i| {
+        format!(
+            "{}{}",
+            i / per_row + 1,
+            SEAT_LETTERS[(i % per_row) as usize]
+        )
+    })
+}
+
+/// Whether the seat exists in a cabin with the given capacity.
+pub fn is_valid_seat(seat: &str, capacity: u32) -> bool {
+    seat_numbers(capacity).any(|s| s == seat)
+}
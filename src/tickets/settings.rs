@@ -0,0 +1,66 @@
+use std::time::Duration;
+
+use crate::config::{Options, PricingStrategyKind};
+
+use super::pricing::{
+    DeparturePricing, FixedFare, LoadFactorPricing, Pricing, PricingStrategy,
+    DEFAULT_DEPARTURE_TIERS, DEFAULT_LOAD_FACTOR_TIERS,
+};
+use super::refunds::RefundRules;
+
+/// Rules the ticket service applies, read from the configuration.
+pub struct TicketsSettings {
+    /// How long an idempotency key is remembered.
+    pub idempotency_ttl: Duration,
+    /// How long before departure check-in opens.
+    pub checkin_window: Duration,
+    /// Shortest time between the arrival of a leg and the departure of the next one.
+    pub min_connection_time: Duration,
+    /// Share of the cabin capacity sold on flights without a policy of their own.
+    pub overbooking_factor: f64,
+    pub refund_rules: RefundRules,
+    pub pricing: Pricing,
+}
+
+impl TryFrom<&Options> for TicketsSettings {
+    type Error = String;
+
+    fn try_from(opt: &Options) -> Result<Self, Self::Error> {
+        if !(opt.overbooking_factor >= 1.0 && opt.overbooking_factor.is_finite()) {
+            return Err("OVERBOOKING_FACTOR must be at least 1".to_string());
+        }
+
+        let refund_rules = opt
+            .refund_rules
+            .parse()
+            .map_err(|e| format!("invalid REFUND_RULES: {e}"))?;
+
+        let pricing_tiers = opt.pricing_tiers.as_deref();
+        let pricing_strategy: Box<dyn PricingStrategy> = match opt.pricing_strategy {
+            PricingStrategyKind::Fixed => Box::new(FixedFare::new(opt.base_fare_cents)),
+            PricingStrategyKind::LoadFactor => Box::new(
+                LoadFactorPricing::new(
+                    opt.base_fare_cents,
+                    pricing_tiers.unwrap_or(DEFAULT_LOAD_FACTOR_TIERS),
+                )
+                .map_err(|e| format!("invalid PRICING_TIERS: {e}"))?,
+            ),
+            PricingStrategyKind::TimeToDeparture => Box::new(
+                DeparturePricing::new(
+                    opt.base_fare_cents,
+                    pricing_tiers.unwrap_or(DEFAULT_DEPARTURE_TIERS),
+                )
+                .map_err(|e| format!("invalid PRICING_TIERS: {e}"))?,
+            ),
+        };
+
+        Ok(Self {
+            idempotency_ttl: Duration::from_secs(opt.idempotency_key_ttl_secs),
+            checkin_window: Duration::from_secs(opt.checkin_window_secs),
+            min_connection_time: Duration::from_secs(opt.min_connection_time_secs),
+            overbooking_factor: opt.overbooking_factor,
+            refund_rules,
+            pricing: Pricing::new(pricing_strategy, opt.fare_currency.clone()),
+        })
+    }
+}
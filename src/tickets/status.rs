@@ -0,0 +1,119 @@
+use crate::proto::ticketsrvc::TicketStatus;
+
+/// Whether a ticket may move from one status to another.
+///
+/// `Deleted` is only reached through `DeleteTicket`, and `Boarded`, `Refunded` and `Deleted`
+/// are final.
+pub fn can_transition(from: TicketStatus, to: TicketStatus) -> bool {
+    use TicketStatus::*;
+
+    matches!(
+        (from, to),
+        (Valid, CheckedIn | NoShow | Refunded | FlightCancelled)
+            | (CheckedIn, Valid | Boarded | NoShow | FlightCancelled)
+            | (NoShow, Refunded)
+            | (FlightCancelled, Refunded | Valid)
+    )
+}
+
+/// Statuses of the tickets whose passenger still expects to fly.
+pub const ACTIVE: [TicketStatus; 2] = [TicketStatus::Valid, TicketStatus::CheckedIn];
+
+/// Statuses of the tickets that gave their seat up without being deleted.
+pub const SEATLESS: [TicketStatus; 2] = [TicketStatus::NoShow, TicketStatus::Refunded];
+
+/// Whether a ticket with the stored status holds a seat on its flight.
+pub fn holds_seat(status: &str) -> bool {
+    !SEATLESS.contains(&parse_status(status))
+}
+
+/// Whether a ticket moving from one status to another gives its seat up.
+pub fn releases_seat(from: TicketStatus, to: TicketStatus) -> bool {
+    !SEATLESS.contains(&from) && SEATLESS.contains(&to)
+}
+
+/// Status stored on a ticket.
+pub fn parse_status(status: &str) -> TicketStatus {
+    TicketStatus::from_str_name(status).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALL: [TicketStatus; 7] = [
+        TicketStatus::Valid,
+        TicketStatus::CheckedIn,
+        TicketStatus::Boarded,
+        TicketStatus::NoShow,
+        TicketStatus::Refunded,
+        TicketStatus::FlightCancelled,
+        TicketStatus::Deleted,
+    ];
+
+    #[test]
+    fn transition_table() {
+        use TicketStatus::*;
+
+        let allowed = [
+            (Valid, CheckedIn),
+            (Valid, NoShow),
+            (Valid, Refunded),
+            (Valid, FlightCancelled),
+            (CheckedIn, Valid),
+            (CheckedIn, Boarded),
+            (CheckedIn, NoShow),
+            (CheckedIn, FlightCancelled),
+            (NoShow, Refunded),
+            (FlightCancelled, Refunded),
+            (FlightCancelled, Valid),
+        ];
+        for from in ALL {
+            for to in ALL {
+                assert_eq!(
+                    can_transition(from, to),
+                    allowed.contains(&(from, to)),
+                    "{} to {}",
+                    from.as_str_name(),
+                    to.as_str_name()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn final_statuses_go_nowhere() {
+        for from in [
+            TicketStatus::Boarded,
+            TicketStatus::Refunded,
+            TicketStatus::Deleted,
+        ] {
+            assert!(ALL.iter().all(|&to| !can_transition(from, to)));
+        }
+    }
+
+    #[test]
+    fn seat_released_only_when_leaving_the_seat_holding_statuses() {
+        use TicketStatus::*;
+
+        assert!(releases_seat(Valid, NoShow));
+        assert!(releases_seat(CheckedIn, NoShow));
+        assert!(releases_seat(Valid, Refunded));
+        assert!(releases_seat(FlightCancelled, Refunded));
+        assert!(!releases_seat(NoShow, Refunded));
+        assert!(!releases_seat(CheckedIn, Boarded));
+        assert!(!releases_seat(Valid, FlightCancelled));
+    }
+
+    #[test]
+    fn seatless_tickets_hold_no_seat() {
+        use TicketStatus::*;
+
+        for status in [Valid, CheckedIn, Boarded, FlightCancelled] {
+            assert!(holds_seat(status.as_str_name()));
+        }
+        for status in [NoShow, Refunded] {
+            assert!(!holds_seat(status.as_str_name()));
+        }
+    }
+}